@@ -0,0 +1,101 @@
+use crate::{IdxError,IdxResult};
+
+use serde::{Serialize,de::DeserializeOwned};
+
+/// Serialization format for objects in a storage
+///
+/// Codecs are used as type parameters, as in
+/// `storage.read_as::<Json, MyType>(name)`.
+pub trait Codec {
+    /// Serialize an object to raw bytes
+    fn encode<T: Serialize>(obj: &T) -> IdxResult<Vec<u8>>;
+
+    /// Deserialize an object from raw bytes
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T>;
+}
+
+
+/// JSON using `serde_json`
+///
+/// Unlike the other codecs, failures are reported as `IdxError::JsonError`,
+/// as `read_json` and `write_json` did before codecs existed.
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(obj: &T) -> IdxResult<Vec<u8>> {
+        serde_json::to_vec(obj).map_err(IdxError::from)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T> {
+        serde_json::from_slice(data).map_err(IdxError::from)
+    }
+}
+
+
+/// CBOR using `serde_cbor`
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn encode<T: Serialize>(obj: &T) -> IdxResult<Vec<u8>> {
+        serde_cbor::to_vec(obj).map_err(IdxError::codec_error)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T> {
+        serde_cbor::from_slice(data).map_err(IdxError::codec_error)
+    }
+}
+
+
+/// MessagePack using `rmp-serde`
+///
+/// Structs are encoded as maps, so fields can be added without breaking
+/// existing objects.
+#[cfg(feature = "msgpack")]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(obj: &T) -> IdxResult<Vec<u8>> {
+        rmp_serde::to_vec_named(obj).map_err(IdxError::codec_error)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T> {
+        rmp_serde::from_slice(data).map_err(IdxError::codec_error)
+    }
+}
+
+
+/// TOML using the `toml` crate
+///
+/// Only types serializing to a table can be stored at the top level.
+#[cfg(feature = "toml")]
+pub struct Toml;
+
+#[cfg(feature = "toml")]
+impl Codec for Toml {
+    fn encode<T: Serialize>(obj: &T) -> IdxResult<Vec<u8>> {
+        toml::to_vec(obj).map_err(IdxError::codec_error)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T> {
+        toml::from_slice(data).map_err(IdxError::codec_error)
+    }
+}
+
+
+/// YAML using `serde_yaml`
+#[cfg(feature = "yaml")]
+pub struct Yaml;
+
+#[cfg(feature = "yaml")]
+impl Codec for Yaml {
+    fn encode<T: Serialize>(obj: &T) -> IdxResult<Vec<u8>> {
+        serde_yaml::to_vec(obj).map_err(IdxError::codec_error)
+    }
+
+    fn decode<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T> {
+        serde_yaml::from_slice(data).map_err(IdxError::codec_error)
+    }
+}
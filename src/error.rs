@@ -1,15 +1,30 @@
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum IdxError {
-    StorageError(Box<dyn Error>),
+    StorageError(Box<dyn Error + Send + Sync>),
     JsonError(serde_json::error::Error),
     IndexingError(IndexingError),
+    /// Serializing or deserializing an object with a codec other than `Json`
+    /// failed
+    CodecError(Box<dyn Error + Send + Sync>),
+    /// An object name contains a `.` or `..` segment
+    DotSegment(String),
+    /// A path resolves to a location outside of the storage root
+    SymlinkEscape(PathBuf),
+    /// An object failed authentication when decrypting it
+    Tampered(String),
+    /// The storage can't be modified
+    ReadOnly(String),
+    /// A saved index has a format version this build can't read
+    UnsupportedVersion(u32),
 }
 
 impl IdxError {
-    pub fn storage_error<T: Error + 'static>(e: T) -> Self {
+    pub fn storage_error<T: Error + Send + Sync + 'static>(e: T) -> Self {
         Self::StorageError(Box::new(e))
     }
 
@@ -18,10 +33,37 @@ impl IdxError {
         Self::storage_error(msg)
     }
 
+    pub fn codec_error<T: Error + Send + Sync + 'static>(e: T) -> Self {
+        Self::CodecError(Box::new(e))
+    }
+
     pub fn indexing_error_msg(s: impl Into<String>) -> Self {
         let err = IndexingError::new(s);
         Self::IndexingError(err)
     }
+
+    /// Check if this is a storage error caused by a missing object
+    pub fn is_not_found(&self) -> bool {
+        self.io_error_kind() == Some(io::ErrorKind::NotFound)
+    }
+
+    /// Check if this is a storage error caused by an object in the place of a
+    /// directory
+    pub fn is_not_a_directory(&self) -> bool {
+        self.io_error_kind() == Some(io::ErrorKind::NotADirectory)
+    }
+
+    /// Check if this is a storage error caused by an object that already exists
+    pub fn is_already_exists(&self) -> bool {
+        self.io_error_kind() == Some(io::ErrorKind::AlreadyExists)
+    }
+
+    fn io_error_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            Self::StorageError(err) => err.downcast_ref::<io::Error>().map(|e| e.kind()),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for IdxError {
@@ -38,6 +80,30 @@ impl fmt::Display for IdxError {
             Self::IndexingError(err) => {
                 write!(f, "Indexing error: {}", err)
             }
+
+            Self::CodecError(err) => {
+                write!(f, "Codec error: {}", err)
+            }
+
+            Self::DotSegment(name) => {
+                write!(f, "Dot segments are not allowed in object names: '{}'", name)
+            }
+
+            Self::SymlinkEscape(path) => {
+                write!(f, "Path resolves outside of the storage root: '{}'", path.display())
+            }
+
+            Self::Tampered(name) => {
+                write!(f, "Object was modified or encrypted with a different key: '{}'", name)
+            }
+
+            Self::ReadOnly(name) => {
+                write!(f, "Storage is read-only, can't modify '{}'", name)
+            }
+
+            Self::UnsupportedVersion(version) => {
+                write!(f, "Unsupported format version of saved index: {}", version)
+            }
         }
     }
 }
@@ -0,0 +1,222 @@
+use crate::{IdxError,IdxResult,Message,ObjectName};
+use crate::codec::{Codec,Json};
+
+use serde::de::DeserializeOwned;
+
+/// Serialization formats recognized by `detect_format`
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Format {
+    Json,
+    /// One JSON value per line, decoded as a sequence
+    JsonLines,
+    Cbor,
+    MessagePack,
+    Toml,
+    Yaml,
+}
+
+/// Compression wrapped around the serialized data of an object
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Suffix conventionally appended to names of compressed objects
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Gzip => ".gz",
+            Self::Zstd => ".zst",
+        }
+    }
+}
+
+
+/// Largest decompressed size accepted unless another limit is given, 1 GiB
+///
+/// A small compressed object can expand to enormous sizes, so decompression
+/// stops with a codec error once the limit is exceeded.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 1 << 30;
+
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const CBOR_SELF_DESCRIBE: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+
+/// Find the compression of an object from its extension or magic bytes
+pub fn detect_compression(name: ObjectName<'_>, data: &[u8]) -> Option<Compression> {
+    let name = name.as_str();
+
+    if name.ends_with(".gz") || data.starts_with(&GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else if name.ends_with(".zst") || data.starts_with(&ZSTD_MAGIC) {
+        Some(Compression::Zstd)
+    } else {
+        None
+    }
+}
+
+
+/// Find the format of uncompressed object data
+///
+/// The extension takes precedence, with a compression suffix ignored. Without a
+/// known extension only JSON and self-described CBOR can be recognized.
+pub fn detect_format(name: ObjectName<'_>, data: &[u8]) -> Option<Format> {
+    let name = name.as_str();
+    let name = name.strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(name);
+    let extension = name.rsplit('.').next().filter(|ext| *ext != name);
+
+    let by_extension = match extension {
+        Some("json") => Some(Format::Json),
+        Some("jsonl") | Some("ndjson") => Some(Format::JsonLines),
+        Some("cbor") => Some(Format::Cbor),
+        Some("msgpack") | Some("mpk") => Some(Format::MessagePack),
+        Some("toml") => Some(Format::Toml),
+        Some("yaml") | Some("yml") => Some(Format::Yaml),
+        _ => None,
+    };
+    if by_extension.is_some() {
+        return by_extension;
+    }
+
+    if data.starts_with(&CBOR_SELF_DESCRIBE) {
+        return Some(Format::Cbor);
+    }
+
+    match data.iter().find(|b| !b.is_ascii_whitespace()) {
+        Some(b'{') | Some(b'[') => {
+            if serde_json::from_slice::<serde_json::Value>(data).is_ok() {
+                Some(Format::Json)
+            } else {
+                Some(Format::JsonLines)
+            }
+        }
+        _ => None,
+    }
+}
+
+
+/// Decode object data, detecting compression and format on the way
+///
+/// Compressed data may expand to at most `DEFAULT_MAX_DECOMPRESSED_SIZE`.
+pub fn decode_auto<T: DeserializeOwned>(name: ObjectName<'_>, data: &[u8]) -> IdxResult<T> {
+    decode_auto_limited(name, data, DEFAULT_MAX_DECOMPRESSED_SIZE)
+}
+
+
+/// Decode object data like `decode_auto`, with compressed data expanding to
+/// at most `max_size` bytes
+pub fn decode_auto_limited<T: DeserializeOwned>(name: ObjectName<'_>, data: &[u8], max_size: u64) -> IdxResult<T> {
+    match detect_compression(name, data) {
+        Some(compression) => {
+            let plain = decompress(compression, data, max_size)?;
+            decode_format(detect_format(name, &plain), &plain)
+        }
+        None => decode_format(detect_format(name, data), data),
+    }
+}
+
+
+fn decode_format<T: DeserializeOwned>(format: Option<Format>, data: &[u8]) -> IdxResult<T> {
+    match format {
+        Some(Format::Json) => Json::decode(data),
+        Some(Format::JsonLines) => decode_json_lines(data),
+        #[cfg(feature = "cbor")]
+        Some(Format::Cbor) => crate::codec::Cbor::decode(data),
+        #[cfg(feature = "msgpack")]
+        Some(Format::MessagePack) => crate::codec::MessagePack::decode(data),
+        #[cfg(feature = "toml")]
+        Some(Format::Toml) => crate::codec::Toml::decode(data),
+        #[cfg(feature = "yaml")]
+        Some(Format::Yaml) => crate::codec::Yaml::decode(data),
+        #[allow(unreachable_patterns)]
+        Some(format) => {
+            let msg = format!("Support for {:?} objects is not enabled", format);
+            Err(IdxError::codec_error(Message(msg)))
+        }
+        None => Err(IdxError::codec_error(Message("Unable to detect the object format".to_string()))),
+    }
+}
+
+
+fn decode_json_lines<T: DeserializeOwned>(data: &[u8]) -> IdxResult<T> {
+    let mut values = vec![];
+
+    for line in data.split(|b| *b == b'\n') {
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        values.push(serde_json::from_slice::<serde_json::Value>(line)?);
+    }
+
+    Ok(serde_json::from_value(serde_json::Value::Array(values))?)
+}
+
+
+/// Compress data, using the default level of the algorithm if none is given
+#[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn compress(compression: Compression, level: Option<u32>, data: &[u8]) -> IdxResult<Vec<u8>> {
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            use std::io::Write;
+
+            let level = level.map(flate2::Compression::new).unwrap_or_default();
+            let mut encoder = flate2::write::GzEncoder::new(vec![], level);
+            encoder.write_all(data).map_err(IdxError::codec_error)?;
+            encoder.finish().map_err(IdxError::codec_error)
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let level = level.map(|l| l as i32).unwrap_or(0);
+            zstd::stream::encode_all(data, level).map_err(IdxError::codec_error)
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(not_enabled(compression)),
+    }
+}
+
+
+/// Decompress data, failing once it expands to more than `max_size` bytes
+#[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn decompress(compression: Compression, data: &[u8], max_size: u64) -> IdxResult<Vec<u8>> {
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => read_limited(flate2::read::GzDecoder::new(data), max_size),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let decoder = zstd::stream::read::Decoder::new(data).map_err(IdxError::codec_error)?;
+            read_limited(decoder, max_size)
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(not_enabled(compression)),
+    }
+}
+
+
+/// Read everything from a decoder, but no more than `max_size` bytes
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn read_limited(decoder: impl std::io::Read, max_size: u64) -> IdxResult<Vec<u8>> {
+    use std::io::Read;
+
+    let mut rv = vec![];
+    decoder.take(max_size.saturating_add(1)).read_to_end(&mut rv)
+        .map_err(IdxError::codec_error)?;
+
+    if rv.len() as u64 > max_size {
+        let msg = format!("Decompressed object exceeds the limit of {} bytes", max_size);
+        return Err(IdxError::codec_error(Message(msg)));
+    }
+    Ok(rv)
+}
+
+
+#[cfg_attr(all(feature = "gzip", feature = "zstd"), allow(dead_code))]
+fn not_enabled(compression: Compression) -> IdxError {
+    let msg = format!("Support for {:?} compressed objects is not enabled", compression);
+    IdxError::codec_error(Message(msg))
+}
@@ -1,4 +1,4 @@
-use crate::{IdxError,IdxResult,ObjectName,ObjectNameBuf,AccessStorage,Lookup};
+use crate::{IdxError,IdxResult,ObjectName,ObjectNameBuf,AccessStorage,Lookup,WalkOptions};
 
 use async_trait::async_trait;
 use std::future::Future;
@@ -19,8 +19,23 @@ pub trait Index<'a>
     type Error: Send;
 
     /// Constructor to perform indexing asynchronously
+    ///
+    /// Only the files directly inside `start` are indexed, directories are
+    /// skipped.
     async fn index<S,F,U>(storage: &S, start: ObjectName<'_>, keymap: F)
             -> IdxResult<Self::Lookup>
+        where
+            S: AccessStorage + Sync + Send + Clone + 'static,
+            U: Future<Output = Result<Self::Key, Self::Error>> + Send,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
+    {
+        Self::index_with(storage, start, &WalkOptions::new().include_dirs(false), keymap).await
+    }
+
+    /// Constructor to perform indexing asynchronously on the objects selected
+    /// by walking the storage with the given options
+    async fn index_with<S,F,U>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: F)
+            -> IdxResult<Self::Lookup>
         where
             S: AccessStorage + Sync + Send + Clone + 'static,
             U: Future<Output = Result<Self::Key, Self::Error>> + Send,
@@ -40,8 +55,23 @@ pub trait MultiIndex<'a>
     type Error: Send;
 
     /// Constructor to perform indexing asynchronously
+    ///
+    /// Only the files directly inside `start` are indexed, directories are
+    /// skipped.
     async fn multi_index<S,F,U>(storage: &S, start: ObjectName<'_>, keymap: F)
             -> IdxResult<Self::Lookup>
+        where
+            S: AccessStorage + Sync + Send + Clone + 'static,
+            U: Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
+    {
+        Self::multi_index_with(storage, start, &WalkOptions::new().include_dirs(false), keymap).await
+    }
+
+    /// Constructor to perform indexing asynchronously on the objects selected
+    /// by walking the storage with the given options
+    async fn multi_index_with<S,F,U>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: F)
+            -> IdxResult<Self::Lookup>
         where
             S: AccessStorage + Sync + Send + Clone + 'static,
             U: Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send,
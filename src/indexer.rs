@@ -1 +1,4 @@
+pub(crate) mod options;
 pub(crate) mod hashtable_indexer;
+#[cfg(feature = "watch")]
+pub(crate) mod live_index;
@@ -6,137 +6,573 @@ use crate::{
     Lookup,
     Index,
     MultiIndex,
-    AccessStorage
+    AccessStorage,
+    WalkOptions,
+    IndexOptions,
+    ContentDigest,
+    ObjectMetadata,
+    IdxError,
+    Codec,
+    Json
 };
 
+use futures::{StreamExt,TryFutureExt};
+use futures::stream;
 use tokio::spawn;
 use tokio::sync::mpsc;
+use tokio::io::{AsyncBufReadExt,BufReader};
 use async_trait::async_trait;
-use serde::{Serialize,Deserialize};
-use std::collections::{hash_map,HashMap};
+use serde::{Serialize,Deserialize,de::DeserializeOwned};
+use std::collections::{hash_map,HashMap,HashSet};
 use std::hash::Hash;
 use std::future::Future;
+use std::time::SystemTime;
 
+/// Version of the format `HashTableIndexer::save` writes
+const FORMAT_VERSION: u32 = 1;
 
-#[derive(Serialize,Deserialize)]
+/// Number of objects whose metadata is queried at the same time
+const RECORD_CONCURRENCY: usize = 64;
+
+
+#[derive(Clone,Serialize,Deserialize)]
 pub struct HashTableIndexer<K: Eq + Hash> {
-    map: HashMap<K,Vec<ObjectNameBuf>>
+    map: HashMap<K,Vec<ObjectNameBuf>>,
+    /// Metadata of the objects when they were indexed, if changes are tracked
+    #[serde(default)]
+    recorded: HashMap<String,ObjectMetadata>,
+    // the rest is saved in the header only, and filled in from it on load
+    /// Directory the index was built from
+    #[serde(skip)]
+    root: ObjectNameBuf,
+    /// Time the index was last built or updated
+    #[serde(skip)]
+    built: Option<SystemTime>,
+    #[serde(skip)]
+    keymap: Option<String>,
+    #[serde(skip)]
+    storage: Option<String>,
 }
 
 
-#[async_trait]
-impl<'a, K: 'static + Eq + Hash + Send> Index<'a> for HashTableIndexer<K> {
-    type Key = K;
-    type Lookup = Self;
-    type Error = IndexingError;
+/// Description of a saved `HashTableIndexer`, stored in front of it
+///
+/// The header can be read without loading the index, to check whether the
+/// saved copy is still of use. A copy that is merely outdated can be
+/// loaded and brought up to date with `HashTableIndexer::update`.
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct IndexHeader {
+    version: u32,
+    #[serde(default)]
+    storage: Option<String>,
+    root: ObjectNameBuf,
+    built: Option<SystemTime>,
+    objects: usize,
+    keymap: Option<String>,
+}
 
-    async fn index<S,F,U>(storage: &S, start: ObjectName<'_>, keymap: F)
-            -> IdxResult<Self::Lookup>
+impl IndexHeader {
+    /// Version of the format the index was saved in
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Identifier of the indexed storage, as given to
+    /// `HashTableIndexer::storage_id`
+    pub fn storage_id(&self) -> Option<&str> {
+        self.storage.as_deref()
+    }
+
+    /// Directory the index was built from, within the indexed storage
+    pub fn root(&self) -> ObjectName<'_> {
+        self.root.name()
+    }
+
+    /// Time the index was last built or updated, unknown for indexes
+    /// deserialized from elsewhere
+    pub fn built(&self) -> Option<SystemTime> {
+        self.built
+    }
+
+    /// Number of indexed objects
+    pub fn objects(&self) -> usize {
+        self.objects
+    }
+
+    /// Identifier of the keymap, as given to `HashTableIndexer::keymap_id`
+    pub fn keymap_id(&self) -> Option<&str> {
+        self.keymap.as_deref()
+    }
+}
+
+
+/// Just the version of a header, which is checked before parsing the rest
+#[derive(Deserialize)]
+struct HeaderVersion {
+    version: u32,
+}
+
+
+impl<K: Eq + Hash> HashTableIndexer<K> {
+    /// Name the keymap the index was built with, so saved copies can be
+    /// told apart
+    pub fn keymap_id(mut self, id: impl Into<String>) -> Self {
+        self.keymap = Some(id.into());
+        self
+    }
+
+    /// Name the storage the index was built from, like its base path or
+    /// URL, so saved copies can be matched to it
+    pub fn storage_id(mut self, id: impl Into<String>) -> Self {
+        self.storage = Some(id.into());
+        self
+    }
+
+    /// Describe the index the way `save` records it
+    pub fn header(&self) -> IndexHeader {
+        let objects: HashSet<&ObjectNameBuf> = self.map.values().flatten().collect();
+
+        IndexHeader {
+            version: FORMAT_VERSION,
+            storage: self.storage.clone(),
+            root: self.root.clone(),
+            built: self.built,
+            objects: objects.len(),
+            keymap: self.keymap.clone(),
+        }
+    }
+
+    /// Save the index as a single object
+    ///
+    /// The object holds the header as a line of JSON, followed by the index
+    /// itself as JSON, so keys have to serialize to strings or numbers.
+    /// What the header describes is only saved there, serializing the index
+    /// by other means leaves it out.
+    pub async fn save<S>(&self, storage: &S, name: ObjectName<'_>) -> IdxResult<()>
+        where
+            S: AccessStorage + Sync,
+            K: Serialize
+    {
+        let mut data = Json::encode(&self.header())?;
+        data.push(b'\n');
+        data.extend(Json::encode(self)?);
+
+        storage.write_bytes(name, data).await
+    }
+
+    /// Load an index saved with `save`
+    ///
+    /// Fails with `IdxError::UnsupportedVersion` if it was saved in another
+    /// format version.
+    pub async fn load<S>(storage: &S, name: ObjectName<'_>) -> IdxResult<Self>
+        where
+            S: AccessStorage + Sync,
+            K: DeserializeOwned
+    {
+        let data = storage.read_bytes(name).await?;
+        let split = data.iter().position(|b| *b == b'\n')
+            .ok_or_else(|| not_saved_index(name))?;
+        let header = parse_header(&data[..split])?;
+
+        let mut index: Self = Json::decode(&data[split + 1..])?;
+        index.root = header.root;
+        index.built = header.built;
+        index.keymap = header.keymap;
+        index.storage = header.storage;
+        Ok(index)
+    }
+
+    /// Read the header of a saved index without loading it
+    pub async fn read_header<S>(storage: &S, name: ObjectName<'_>) -> IdxResult<IndexHeader>
+        where
+            S: AccessStorage + Sync
+    {
+        let reader = storage.open_read(name).await?;
+        let mut line = vec![];
+        BufReader::new(reader).read_until(b'\n', &mut line).await?;
+        if line.pop() != Some(b'\n') {
+            return Err(not_saved_index(name));
+        }
+
+        parse_header(&line)
+    }
+
+    #[cfg(feature = "watch")]
+    pub(crate) fn new(root: ObjectNameBuf) -> Self {
+        Self {
+            map: HashMap::new(),
+            recorded: HashMap::new(),
+            root,
+            built: Some(SystemTime::now()),
+            keymap: None,
+            storage: None,
+        }
+    }
+
+    /// Add an object to the names of a key
+    #[cfg(feature = "watch")]
+    pub(crate) fn insert(&mut self, key: K, name: ObjectNameBuf) {
+        self.map.entry(key).or_insert(vec![]).push(name);
+    }
+
+    /// Remove an object from the names of a key, dropping the key with the
+    /// last of them
+    #[cfg(feature = "watch")]
+    pub(crate) fn remove(&mut self, key: &K, name: &ObjectNameBuf) {
+        if let Some(names) = self.map.get_mut(key) {
+            if let Some(pos) = names.iter().position(|n| n == name) {
+                names.swap_remove(pos);
+            }
+            if names.is_empty() {
+                self.map.remove(key);
+            }
+        }
+    }
+}
+
+
+impl<K: 'static + Eq + Hash + Send> HashTableIndexer<K> {
+    /// Index the objects selected by the walk options like
+    /// `Index::index_with`, running the keymap as the index options select
+    pub async fn index_with_options<S,F,U>(storage: &S, start: ObjectName<'_>, walk: &WalkOptions,
+                                           options: &IndexOptions, keymap: F)
+            -> IdxResult<Self>
         where
             S: AccessStorage + Clone + Send + Sync + 'static,
-            U: Future<Output = Result<Self::Key, Self::Error>> + Send,
+            U: Future<Output = Result<K, IndexingError>> + Send,
             F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
     {
-        // Set up a channel to return computed keys from indexing tasks
-        let (tx, mut rx) = mpsc::channel(100);
-        // Vec for task JoinHandles
-        let mut index_tasks = Vec::new();
-
-        // List files in storage and start a task for each one
-        for file in storage.list(start).await?.into_iter() {
-            let f = ObjectNameBuf::from_str(&file)?;
-
-            let handle = {
-                // clone everything to pass to the async block inside the task
-                // data in the task has to have 'static lifetime
-                let mut tx = tx.clone();
-                let storage = storage.clone();
-                let keymap: F = keymap.clone();
-
-                spawn(async move {
-                    let key = keymap(storage, f.clone()).await;
-                    if let Err(_) = tx.send((key, f)).await {
-                        panic!("Unexpected error: receiver dropped");
-                    }
-                })
-            };
-            index_tasks.push(handle);
+        let built = SystemTime::now();
+        let (mut rx, recorded) = spawn_keymaps(storage, start, walk, options, keymap).await?;
+
+        // collect results from channel into index HashMap
+        let mut map = HashMap::new();
+        while let Some((key, filenames)) = rx.recv().await {
+            map.entry(key?).or_insert(vec![]).extend(filenames);
         }
 
-        // need to drop receiver threads/tasks Sender, so that while loop below can terminate 
-        drop(tx);
+        let rv = Self {
+            map: map,
+            recorded,
+            root: start.into(),
+            built: Some(built),
+            keymap: None,
+            storage: None
+        };
+
+        Ok(rv)
+    }
+
+    /// Index the objects selected by the walk options like
+    /// `MultiIndex::multi_index_with`, running the keymap as the index
+    /// options select
+    pub async fn multi_index_with_options<S,F,U>(storage: &S, start: ObjectName<'_>, walk: &WalkOptions,
+                                                 options: &IndexOptions, keymap: F)
+            -> IdxResult<Self>
+        where
+            S: AccessStorage + Clone + Send + Sync + 'static,
+            U: Future<Output = Result<Vec<K>, IndexingError>> + Send,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
+    {
+        let built = SystemTime::now();
+        let (mut rx, recorded) = spawn_keymaps(storage, start, walk, options, keymap).await?;
 
         // collect results from channel into index HashMap
         let mut map = HashMap::new();
-        while let Some((key, filename)) = rx.recv().await {
-            map.entry(key?).or_insert(vec![]).push(filename);
+        while let Some((keys, filenames)) = rx.recv().await {
+            for key in keys? {
+                map.entry(key).or_insert(vec![]).extend(filenames.iter().cloned());
+            }
         }
 
         let rv = Self {
-            map: map
+            map: map,
+            recorded,
+            root: start.into(),
+            built: Some(built),
+            keymap: None,
+            storage: None
         };
 
         Ok(rv)
     }
+
+    /// Bring the index up to date with a keymap returning one key per
+    /// object, see `multi_update`
+    pub async fn update<S,F,U>(&mut self, storage: &S, start: ObjectName<'_>, walk: &WalkOptions,
+                               options: &IndexOptions, keymap: F)
+            -> IdxResult<()>
+        where
+            S: AccessStorage + Clone + Send + Sync + 'static,
+            U: Future<Output = Result<K, IndexingError>> + Send,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
+    {
+        self.multi_update(storage, start, walk, options, move |storage, name| keymap(storage, name).map_ok(|key| vec![key]))
+            .await
+    }
+
+    /// Bring the index up to date with the storage, running the keymap only
+    /// for objects that are new or changed since they were indexed
+    ///
+    /// The start directory, options and keymap have to be the ones the index
+    /// was built with. Objects are compared by digest if the storage
+    /// knows it, otherwise by size and modification time. As a change right
+    /// after an object was recorded may leave its modification time as it
+    /// was, objects modified at or after the time the index was last built
+    /// count as changed. Objects without
+    /// recorded metadata count as changed, so an index built without
+    /// `IndexOptions::track_changes` is indexed completely once, but the
+    /// metadata is recorded from then on. If the keymap fails, the index is
+    /// left as it was.
+    pub async fn multi_update<S,F,U>(&mut self, storage: &S, start: ObjectName<'_>, walk: &WalkOptions,
+                                     options: &IndexOptions, keymap: F)
+            -> IdxResult<()>
+        where
+            S: AccessStorage + Clone + Send + Sync + 'static,
+            U: Future<Output = Result<Vec<K>, IndexingError>> + Send,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
+    {
+        let built = SystemTime::now();
+        let objects = storage.walk(start, walk).await?;
+        let current = record_all(storage, &objects).await?;
+        let mut changed = vec![];
+        for name in objects.into_iter() {
+            let same = {
+                let name = name.name();
+                self.recorded.get(name.as_str())
+                    .is_some_and(|old| unchanged(old, &current[name.as_str()], self.built))
+            };
+            if !same {
+                changed.push(name);
+            }
+        }
+
+        let stale: HashSet<String> = changed.iter()
+            .map(|name| name.name().as_str().to_string())
+            .collect();
+        let groups = group_by_content(storage, changed, options).await?;
+        let mut rx = spawn_groups(storage, groups, keymap);
+        let mut results = vec![];
+        while let Some((keys, filenames)) = rx.recv().await {
+            results.push((keys?, filenames));
+        }
+
+        // drop the objects that are gone or get new keys
+        for names in self.map.values_mut() {
+            names.retain(|name| {
+                let name = name.name();
+                current.contains_key(name.as_str()) && !stale.contains(name.as_str())
+            });
+        }
+        self.map.retain(|_, names| !names.is_empty());
+
+        for (keys, filenames) in results.into_iter() {
+            for key in keys {
+                self.map.entry(key).or_insert(vec![]).extend(filenames.iter().cloned());
+            }
+        }
+        self.recorded = current;
+        self.root = start.into();
+        self.built = Some(built);
+
+        Ok(())
+    }
+}
+
+
+fn parse_header(line: &[u8]) -> IdxResult<IndexHeader> {
+    let version: HeaderVersion = Json::decode(line)?;
+    if version.version != FORMAT_VERSION {
+        return Err(IdxError::UnsupportedVersion(version.version));
+    }
+
+    Json::decode(line)
+}
+
+fn not_saved_index(name: ObjectName<'_>) -> IdxError {
+    IdxError::storage_error_msg(format!("Not a saved index: '{}'", name.as_str()))
+}
+
+
+/// Query the metadata `update` compares, with the digest if the storage
+/// knows it without reading the object
+///
+/// The digest is only asked for separately if `stat` didn't include it.
+async fn record<S: AccessStorage + Sync>(storage: &S, name: ObjectName<'_>) -> IdxResult<ObjectMetadata> {
+    let metadata = storage.stat(name, false).await?;
+    if metadata.digest().is_some() {
+        return Ok(metadata);
+    }
+    let digest = storage.content_digest(name).await?;
+
+    Ok(ObjectMetadata::new(metadata.size(), metadata.modified(), digest))
+}
+
+/// Query the metadata of all objects concurrently, keyed by name
+///
+/// At most `RECORD_CONCURRENCY` queries run at the same time.
+async fn record_all<S: AccessStorage + Sync>(storage: &S, objects: &[ObjectNameBuf])
+        -> IdxResult<HashMap<String,ObjectMetadata>>
+{
+    let queries: Vec<_> = objects.iter()
+        .map(|name| record(storage, name.name()).map_ok(move |metadata| (name, metadata)))
+        .collect();
+    let mut pending = stream::iter(queries).buffer_unordered(RECORD_CONCURRENCY);
+
+    let mut rv = HashMap::with_capacity(objects.len());
+    while let Some(res) = pending.next().await {
+        let (name, metadata) = res?;
+        rv.insert(name.name().as_str().to_string(), metadata);
+    }
+
+    Ok(rv)
+}
+
+/// Check whether an object is the same as when its metadata was recorded by
+/// the build at `built`
+///
+/// Modification times only count if they are older than the build, as the
+/// object may have changed again within the resolution of the timestamps.
+fn unchanged(old: &ObjectMetadata, new: &ObjectMetadata, built: Option<SystemTime>) -> bool {
+    old.size() == new.size() && match (old.digest(), new.digest()) {
+        (Some(old), Some(new)) => old == new,
+        _ => match (old.modified(), built) {
+            (Some(modified), Some(built)) => modified < built && new.modified() == Some(modified),
+            _ => false,
+        },
+    }
+}
+
+
+/// Walk the storage and run the keymap on the objects found, see
+/// `spawn_groups`
+///
+/// The metadata of the objects is returned as well if
+/// `IndexOptions::track_changes` is set.
+pub(crate) async fn spawn_keymaps<S,F,U,T,E>(storage: &S, start: ObjectName<'_>, walk: &WalkOptions,
+                                             options: &IndexOptions, keymap: F)
+        -> IdxResult<(mpsc::Receiver<(Result<T,E>, Vec<ObjectNameBuf>)>, HashMap<String,ObjectMetadata>)>
+    where
+        S: AccessStorage + Clone + Send + Sync + 'static,
+        U: Future<Output = Result<T,E>> + Send,
+        F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static,
+        T: Send + 'static,
+        E: Send + 'static
+{
+    let objects = storage.walk(start, walk).await?;
+    let recorded = if options.tracks_changes() {
+        record_all(storage, &objects).await?
+    } else {
+        HashMap::new()
+    };
+
+    let groups = group_by_content(storage, objects, options).await?;
+    Ok((spawn_groups(storage, groups, keymap), recorded))
+}
+
+
+/// Group the objects sharing the same contents
+///
+/// This only happens if `IndexOptions::dedup_content` is set and the storage
+/// knows the digests, otherwise every object is in a group of its own.
+async fn group_by_content<S>(storage: &S, objects: Vec<ObjectNameBuf>, options: &IndexOptions)
+        -> IdxResult<Vec<Vec<ObjectNameBuf>>>
+    where
+        S: AccessStorage + Sync
+{
+    let mut groups: Vec<Vec<ObjectNameBuf>> = Vec::new();
+    let mut by_digest: HashMap<ContentDigest,usize> = HashMap::new();
+    for f in objects.into_iter() {
+        let digest = if options.dedups_content() {
+            storage.content_digest(f.name()).await?
+        } else {
+            None
+        };
+
+        match digest.map(|digest| by_digest.entry(digest)) {
+            Some(hash_map::Entry::Occupied(entry)) => groups[*entry.get()].push(f),
+            Some(hash_map::Entry::Vacant(entry)) => {
+                entry.insert(groups.len());
+                groups.push(vec![f]);
+            }
+            None => groups.push(vec![f]),
+        }
+    }
+
+    Ok(groups)
+}
+
+
+/// Run the keymap on the first object of every group, each in its own task
+///
+/// Every result is sent along with the names of its group, which all share
+/// it.
+pub(crate) fn spawn_groups<S,F,U,T,E>(storage: &S, groups: Vec<Vec<ObjectNameBuf>>, keymap: F)
+        -> mpsc::Receiver<(Result<T,E>, Vec<ObjectNameBuf>)>
+    where
+        S: AccessStorage + Clone + Send + Sync + 'static,
+        U: Future<Output = Result<T,E>> + Send,
+        F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static,
+        T: Send + 'static,
+        E: Send + 'static
+{
+    // Set up a channel to return computed keys from indexing tasks
+    let (tx, rx) = mpsc::channel(100);
+
+    // Start a task for each group of objects
+    for group in groups.into_iter() {
+        // clone everything to pass to the async block inside the task
+        // data in the task has to have 'static lifetime
+        let mut tx = tx.clone();
+        let storage = storage.clone();
+        let keymap: F = keymap.clone();
+
+        spawn(async move {
+            let key = keymap(storage, group[0].clone()).await;
+            if tx.send((key, group)).await.is_err() {
+                panic!("Unexpected error: receiver dropped");
+            }
+        });
+    }
+
+    // the sender kept here is dropped on return, so the receiver terminates
+    // once all tasks are done
+    rx
 }
 
 
 #[async_trait]
-impl<'a, K: 'static + Eq + Hash + Send> MultiIndex<'a> for HashTableIndexer<K> {
+impl<'a, K: 'static + Eq + Hash + Send> Index<'a> for HashTableIndexer<K> {
     type Key = K;
     type Lookup = Self;
     type Error = IndexingError;
 
-    async fn multi_index<S,F,U>(storage: &S, start: ObjectName<'_>, keymap: F)
+    async fn index_with<S,F,U>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: F)
             -> IdxResult<Self::Lookup>
         where
             S: AccessStorage + Clone + Send + Sync + 'static,
-            U: Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send,
+            U: Future<Output = Result<Self::Key, Self::Error>> + Send,
             F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
     {
-        // Set up a channel to return computed keys from indexing tasks
-        let (tx, mut rx) = mpsc::channel(100);
-        // Vec for task JoinHandles
-        let mut index_tasks = Vec::new();
-
-        // List files in storage and start a task for each one
-        for file in storage.list(start).await?.into_iter() {
-            let f = ObjectNameBuf::from_str(&file)?;
-
-            let handle = {
-                // clone everything to pass to the async block inside the task
-                // data in the task has to have 'static lifetime
-                let mut tx = tx.clone();
-                let storage = storage.clone();
-                let keymap: F = keymap.clone();
-
-                spawn(async move {
-                    let keys = keymap(storage, f.clone()).await;
-                    if let Err(_) = tx.send((keys, f)).await {
-                        panic!("Unexpected error: receiver dropped");
-                    }
-                })
-            };
-            index_tasks.push(handle);
-        }
-
-        // need to drop receiver threads/tasks Sender, so that while loop below can terminate 
-        drop(tx);
+        Self::index_with_options(storage, start, options, &IndexOptions::new(), keymap).await
+    }
+}
 
-        // collect results from channel into index HashMap
-        let mut map = HashMap::new();
-        while let Some((keys, filename)) = rx.recv().await {
-            for key in keys? {
-                let filename_cpy = filename.clone();
-                map.entry(key).or_insert(vec![]).push(filename_cpy);
-            }
-        }
 
-        let rv = Self {
-            map: map
-        };
+#[async_trait]
+impl<'a, K: 'static + Eq + Hash + Send> MultiIndex<'a> for HashTableIndexer<K> {
+    type Key = K;
+    type Lookup = Self;
+    type Error = IndexingError;
 
-        Ok(rv)
+    async fn multi_index_with<S,F,U>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: F)
+            -> IdxResult<Self::Lookup>
+        where
+            S: AccessStorage + Clone + Send + Sync + 'static,
+            U: Future<Output = Result<Vec<Self::Key>, Self::Error>> + Send,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + Clone + 'static
+    {
+        Self::multi_index_with_options(storage, start, options, &IndexOptions::new(), keymap).await
     }
 }
 
@@ -0,0 +1,332 @@
+use super::hashtable_indexer::{spawn_keymaps,spawn_groups};
+use crate::{
+    IdxResult,
+    IndexingResult,
+    ObjectName,
+    ObjectNameBuf,
+    AccessStorage,
+    WalkOptions,
+    IndexOptions,
+    SymlinkPolicy,
+    EntryKind,
+    HashTableIndexer,
+    WatchEvent
+};
+
+use futures::{Stream,StreamExt};
+use futures::future::{BoxFuture,FutureExt,TryFutureExt};
+use tokio::spawn;
+use tokio::task::JoinHandle;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc,PoisonError,RwLock};
+
+
+type Keymap<K,S> = Arc<dyn Fn(S, ObjectNameBuf) -> BoxFuture<'static, IndexingResult<Vec<K>>> + Send + Sync>;
+
+/// Indexed objects by name, along with their keys
+type Objects<K> = BTreeMap<String, (ObjectNameBuf, Vec<K>)>;
+
+
+/// Index kept up to date with the changes to a storage
+///
+/// Each change only runs the keymap again for the objects it affects and
+/// updates the index in place. Lookups go through `snapshot`, which stays
+/// unchanged while it is held, so readers always see a consistent index.
+/// Holding a snapshot while a change comes in makes the update copy the
+/// index once.
+///
+/// Changes are passed in as `WatchEvent`s, either one at a time with
+/// `apply` or by handing a stream like `FileStorage::watch` to `subscribe`.
+/// Unlike with `Index`, objects for which the keymap fails are left out of
+/// the index instead of failing it, as they may be fixed by a later change.
+/// Symlinks to directories appearing later are never descended into.
+#[derive(Clone)]
+pub struct LiveIndex<K: Eq + Hash,S> {
+    shared: Arc<Shared<K,S>>,
+}
+
+
+struct Shared<K: Eq + Hash,S> {
+    storage: S,
+    start: ObjectNameBuf,
+    options: WalkOptions,
+    keymap: Keymap<K,S>,
+    current: RwLock<Arc<HashTableIndexer<K>>>,
+    // also keeps changes from being applied concurrently
+    objects: tokio::sync::Mutex<Objects<K>>,
+}
+
+
+impl<K,S> LiveIndex<K,S>
+    where
+        K: 'static + Eq + Hash + Clone + Send + Sync,
+        S: AccessStorage + Clone + Send + Sync + 'static
+{
+    /// Index the objects selected by the walk options with a keymap
+    /// returning one key per object, see `Index::index_with`
+    ///
+    /// To not miss any change, start watching the storage before building
+    /// the index.
+    pub async fn index_with<F,U>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: F)
+            -> IdxResult<Self>
+        where
+            U: Future<Output = IndexingResult<K>> + Send + 'static,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + 'static
+    {
+        let keymap: Keymap<K,S> = Arc::new(move |storage, name| {
+            keymap(storage, name).map_ok(|key| vec![key]).boxed()
+        });
+
+        Self::build(storage, start, options, keymap).await
+    }
+
+    /// Index the objects selected by the walk options with a keymap
+    /// returning any number of keys per object, see
+    /// `MultiIndex::multi_index_with`
+    pub async fn multi_index_with<F,U>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: F)
+            -> IdxResult<Self>
+        where
+            U: Future<Output = IndexingResult<Vec<K>>> + Send + 'static,
+            F: Fn(S, ObjectNameBuf) -> U + Send + Sync + 'static
+    {
+        let keymap: Keymap<K,S> = Arc::new(move |storage, name| keymap(storage, name).boxed());
+
+        Self::build(storage, start, options, keymap).await
+    }
+
+    async fn build(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: Keymap<K,S>)
+            -> IdxResult<Self>
+    {
+        let (index, objects) = rebuild(storage, start, options, &keymap).await?;
+        let shared = Shared {
+            storage: storage.clone(),
+            start: start.into(),
+            options: options.clone(),
+            keymap,
+            current: RwLock::new(Arc::new(index)),
+            objects: tokio::sync::Mutex::new(objects),
+        };
+
+        Ok(Self {
+            shared: Arc::new(shared)
+        })
+    }
+
+
+    /// The current state of the index
+    pub fn snapshot(&self) -> Arc<HashTableIndexer<K>> {
+        self.shared.current.read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+
+    /// Update the index for a change to the storage
+    ///
+    /// Created objects and renamed directories are indexed with everything
+    /// below them the walk options allow. A `Rescan` builds the whole index
+    /// again. Readers see the index either before or after the change.
+    pub async fn apply(&self, event: WatchEvent) -> IdxResult<()> {
+        let shared = &self.shared;
+        let mut objects = shared.objects.lock().await;
+
+        let (removed, found) = match event {
+            WatchEvent::Created(name) => {
+                let found = shared.find(name.name(), true).await?;
+                (indexed_below(&objects, &name), found)
+            }
+            WatchEvent::Modified(name) => {
+                let found = shared.find(name.name(), false).await?;
+                (vec![name], found)
+            }
+            WatchEvent::Deleted(name) => (indexed_below(&objects, &name), vec![]),
+            WatchEvent::Renamed(from, to) => {
+                let found = shared.find(to.name(), true).await?;
+                // the rename may have replaced objects at the new name
+                let mut removed = indexed_below(&objects, &from);
+                removed.extend(indexed_below(&objects, &to));
+                (removed, found)
+            }
+            WatchEvent::Rescan => {
+                let (index, rebuilt) = rebuild(&shared.storage, shared.start.name(), &shared.options, &shared.keymap)
+                    .await?;
+                *objects = rebuilt;
+                *shared.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(index);
+                return Ok(());
+            }
+        };
+
+        let keymap = shared.keymap.clone();
+        let groups = found.into_iter().map(|name| vec![name]).collect();
+        let mut rx = spawn_groups(&shared.storage, groups, move |storage, name| keymap(storage, name));
+        let mut added = vec![];
+        while let Some((keys, mut names)) = rx.recv().await {
+            if let (Ok(keys), Some(name)) = (keys, names.pop()) {
+                added.push((name, keys));
+            }
+        }
+
+        shared.update(&mut objects, removed, added);
+        Ok(())
+    }
+
+
+    /// Apply the changes from a stream of events in a background task
+    ///
+    /// The task ends with the stream or once the last handle to the index is
+    /// dropped. If a change fails to apply, the whole index is built again,
+    /// and if that fails as well, the index stays as it was until the next
+    /// change.
+    pub fn subscribe<E>(&self, events: E) -> JoinHandle<()>
+        where
+            E: Stream<Item = WatchEvent> + Send + Unpin + 'static
+    {
+        let shared = Arc::downgrade(&self.shared);
+        let mut events = events;
+
+        spawn(async move {
+            while let Some(event) = events.next().await {
+                let index = match shared.upgrade() {
+                    Some(shared) => Self { shared },
+                    None => break,
+                };
+
+                let failed = index.apply(event).await.is_err();
+                if failed {
+                    let _ = index.apply(WatchEvent::Rescan).await;
+                }
+            }
+        })
+    }
+}
+
+
+impl<K,S> Shared<K,S>
+    where
+        K: 'static + Eq + Hash + Clone + Send + Sync,
+        S: AccessStorage + Clone + Send + Sync + 'static
+{
+    /// Find the objects the walk options select at or, if `descend` is set,
+    /// below a name
+    async fn find(&self, name: ObjectName<'_>, descend: bool) -> IdxResult<Vec<ObjectNameBuf>> {
+        let depth = match self.depth_of(name) {
+            Some(depth) if self.options.descends_below(depth - 1) => depth,
+            _ => return Ok(vec![]),
+        };
+
+        let parent = match name.as_str().rfind('/') {
+            Some(pos) => ObjectName::from_path(&name.as_str()[..pos])?,
+            None => ObjectName::empty(),
+        };
+        let entries = match self.storage.list_entries(parent).await {
+            Ok(entries) => entries,
+            // gone again before the change got here
+            Err(e) if e.is_not_found() => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let kind = match entries.iter().find(|entry| entry.name() == name) {
+            Some(entry) => entry.kind(),
+            None => return Ok(vec![]),
+        };
+
+        let mut rv = vec![];
+        match kind {
+            EntryKind::Symlink if self.options.symlink_policy() == SymlinkPolicy::Skip => (),
+            EntryKind::Dir => {
+                if self.options.includes_dirs() {
+                    rv.push(name.into());
+                }
+                if descend && self.options.descends_below(depth) {
+                    let options = match self.options.depth_limit() {
+                        Some(max) => self.options.clone().max_depth(max - depth),
+                        None => self.options.clone(),
+                    };
+                    let below = self.storage.walk(name, &options).await?;
+                    rv.extend(below);
+                }
+            }
+            _ => rv.push(name.into()),
+        }
+
+        Ok(rv)
+    }
+
+    /// Number of path segments of a name below the start directory
+    fn depth_of(&self, name: ObjectName<'_>) -> Option<usize> {
+        let start = self.start.name();
+        let rest = if start.as_str().is_empty() {
+            name.as_str()
+        } else {
+            name.as_str().strip_prefix(start.as_str())?.strip_prefix('/')?
+        };
+
+        Some(rest.split('/').count())
+    }
+
+    /// Replace the keys of the removed objects by those of the added ones
+    fn update(&self, objects: &mut Objects<K>, removed: Vec<ObjectNameBuf>, added: Vec<(ObjectNameBuf, Vec<K>)>) {
+        let mut current = self.current.write().unwrap_or_else(PoisonError::into_inner);
+        // only copies the index if a reader holds on to it
+        let index = Arc::make_mut(&mut current);
+
+        for name in removed {
+            if let Some((name, keys)) = objects.remove(name.name().as_str()) {
+                for key in keys.iter() {
+                    index.remove(key, &name);
+                }
+            }
+        }
+
+        for (name, keys) in added {
+            for key in keys.iter() {
+                index.insert(key.clone(), name.clone());
+            }
+            objects.insert(name.name().as_str().to_string(), (name, keys));
+        }
+    }
+}
+
+
+/// Index everything below the start directory from scratch
+async fn rebuild<K,S>(storage: &S, start: ObjectName<'_>, options: &WalkOptions, keymap: &Keymap<K,S>)
+        -> IdxResult<(HashTableIndexer<K>, Objects<K>)>
+    where
+        K: 'static + Eq + Hash + Clone + Send + Sync,
+        S: AccessStorage + Clone + Send + Sync + 'static
+{
+    let keymap = keymap.clone();
+    let (mut rx, _) = spawn_keymaps(storage, start, options, &IndexOptions::new(), move |storage, name| keymap(storage, name)).await?;
+
+    let mut index = HashTableIndexer::new(start.into());
+    let mut objects = Objects::new();
+    while let Some((keys, names)) = rx.recv().await {
+        let keys = match keys {
+            Ok(keys) => keys,
+            Err(_) => continue,
+        };
+        for name in names {
+            for key in keys.iter() {
+                index.insert(key.clone(), name.clone());
+            }
+            objects.insert(name.name().as_str().to_string(), (name, keys.clone()));
+        }
+    }
+
+    Ok((index, objects))
+}
+
+
+/// Names of the indexed objects at or below a name
+fn indexed_below<K>(objects: &Objects<K>, name: &ObjectNameBuf) -> Vec<ObjectNameBuf> {
+    let name = name.name();
+    let prefix = format!("{}/", name.as_str());
+
+    objects.get(name.as_str()).into_iter()
+        .chain(objects.range(prefix.clone()..)
+            .take_while(|(n, _)| n.starts_with(&prefix))
+            .map(|(_, entry)| entry))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
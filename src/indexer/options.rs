@@ -0,0 +1,55 @@
+/// Options controlling how indexers run the keymap on the objects a walk
+/// selects
+///
+/// The default runs the keymap once for every object and records nothing
+/// else.
+#[derive(Clone,Debug)]
+pub struct IndexOptions {
+    dedup_content: bool,
+    track_changes: bool,
+}
+
+impl IndexOptions {
+    pub fn new() -> Self {
+        Self {
+            dedup_content: false,
+            track_changes: false,
+        }
+    }
+
+    /// Select whether the keymap runs only once for objects with identical
+    /// contents
+    ///
+    /// Only applies to storages that know digests without reading objects,
+    /// see `AccessStorage::content_digest`. The objects then share the keys
+    /// computed for the first of them, so the keymap must only depend on the
+    /// contents.
+    pub fn dedup_content(mut self, enable: bool) -> Self {
+        self.dedup_content = enable;
+        self
+    }
+
+    /// Select whether the metadata of every object is recorded, so
+    /// `HashTableIndexer::update` can tell which objects changed since
+    ///
+    /// This queries the metadata of each object on top of running the
+    /// keymap.
+    pub fn track_changes(mut self, enable: bool) -> Self {
+        self.track_changes = enable;
+        self
+    }
+
+    pub fn dedups_content(&self) -> bool {
+        self.dedup_content
+    }
+
+    pub fn tracks_changes(&self) -> bool {
+        self.track_changes
+    }
+}
+
+impl Default for IndexOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
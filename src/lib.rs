@@ -1,4 +1,6 @@
 mod error;
+mod codec;
+mod format;
 mod names;
 mod storage;
 mod lookup;
@@ -7,19 +9,55 @@ mod index;
 mod indexer;
 
 pub use error::*;
+pub use codec::{Codec,Json};
+#[cfg(feature = "cbor")]
+pub use codec::Cbor;
+#[cfg(feature = "msgpack")]
+pub use codec::MessagePack;
+#[cfg(feature = "toml")]
+pub use codec::Toml;
+#[cfg(feature = "yaml")]
+pub use codec::Yaml;
+pub use format::{Format,Compression,DEFAULT_MAX_DECOMPRESSED_SIZE,detect_format,detect_compression,decode_auto,decode_auto_limited};
 pub use names::*;
-pub use storage::AccessStorage;
-pub use storage::fs::FileStorage;
+pub use storage::{AccessStorage,ObjectReader,ObjectWriter};
+pub use storage::walk::{WalkOptions,SymlinkPolicy};
+pub use storage::entry::{DirEntry,EntryKind};
+pub use storage::metadata::{ObjectMetadata,ContentDigest,DigestBuilder};
+pub use storage::fs::{FileStorage,NonUnicodePolicy};
+#[cfg(feature = "watch")]
+pub use storage::fs::watch::{Watch,WatchEvent};
+pub use storage::memory::MemoryStorage;
+pub use storage::cached::CachedStorage;
+pub use storage::compressed::CompressedStorage;
+pub use storage::overlay::OverlayStorage;
+pub use storage::content_addressed::ContentAddressedStorage;
+#[cfg(feature = "encryption")]
+pub use storage::encrypted::EncryptedStorage;
+#[cfg(feature = "archive")]
+pub use storage::archive::{TarStorage,ZipStorage};
+#[cfg(feature = "git")]
+pub use storage::git::GitStorage;
+#[cfg(feature = "sqlite")]
+pub use storage::sqlite::SqliteStorage;
+#[cfg(feature = "s3")]
+pub use storage::s3::S3Storage;
+#[cfg(feature = "http")]
+pub use storage::http::HttpStorage;
 pub use lookup::Lookup;
 pub use scored_lookup::find_best_match;
 pub use index::{Index,MultiIndex};
-pub use indexer::hashtable_indexer::HashTableIndexer;
+pub use indexer::options::IndexOptions;
+pub use indexer::hashtable_indexer::{HashTableIndexer,IndexHeader};
+#[cfg(feature = "watch")]
+pub use indexer::live_index::LiveIndex;
 
 #[cfg(test)]
 mod tests {
     use serde::{Serialize,Deserialize};
     use temp_testdir::TempDir;
     use tokio_test::block_on;
+    use tokio::io::{AsyncReadExt,AsyncWriteExt};
     use crate::{
         AccessStorage,
         ObjectName,
@@ -30,9 +68,21 @@ mod tests {
         Lookup,
         IndexingError,
         IndexingResult,
+        IdxError,
+        Codec,
+        Json,
+        WalkOptions,
+        IndexOptions,
+        SymlinkPolicy,
+        EntryKind,
+        ContentDigest,
         find_best_match,
     };
-    use crate::storage::fs::FileStorage;
+    use crate::storage::fs::{FileStorage,NonUnicodePolicy};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::cached::CachedStorage;
+    use crate::storage::overlay::OverlayStorage;
+    use crate::storage::content_addressed::ContentAddressedStorage;
 
     #[test]
     fn test_object_naming() {
@@ -43,6 +93,54 @@ mod tests {
         assert!(ObjectName::new("föö.bär").is_ok());
     }
 
+    /// Storage implementing only the required methods
+    struct MinimalStorage(MemoryStorage);
+
+    #[async_trait::async_trait]
+    impl AccessStorage for MinimalStorage {
+        type ListIntoIter = Vec<String>;
+
+        async fn list(&self, dir_name: ObjectName<'_>) -> crate::IdxResult<Self::ListIntoIter> {
+            self.0.list(dir_name).await
+        }
+
+        async fn read_bytes(&self, obj_name: ObjectName<'_>) -> crate::IdxResult<Vec<u8>> {
+            self.0.read_bytes(obj_name).await
+        }
+
+        async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> crate::IdxResult<()>
+            where
+                T: AsRef<[u8]> + Unpin + Send
+        {
+            self.0.write_bytes(name, data).await
+        }
+    }
+
+    #[test]
+    fn test_default_storage_methods() {
+        let sto = MinimalStorage(MemoryStorage::new());
+        let obj = ObjectName::new("obj").unwrap();
+        let other = ObjectName::new("other").unwrap();
+        let is_unsupported = |err: IdxError| match err {
+            IdxError::StorageError(err) => err.downcast_ref::<std::io::Error>()
+                .is_some_and(|err| err.kind() == std::io::ErrorKind::Unsupported),
+            _ => false,
+        };
+
+        block_on(async {
+            sto.write_bytes(obj, b"data").await.unwrap();
+            assert_eq!(4, sto.stat(obj, false).await.unwrap().size());
+            sto.copy(obj, other).await.unwrap();
+            assert!(sto.exists(other).await.unwrap());
+
+            assert!(is_unsupported(sto.list_entries(ObjectName::empty()).await.unwrap_err()));
+            assert!(is_unsupported(sto.open_write(obj).await.err().unwrap()));
+            assert!(is_unsupported(sto.delete(obj).await.unwrap_err()));
+            assert!(is_unsupported(sto.rename(obj, ObjectName::new("moved").unwrap()).await.unwrap_err()));
+            assert!(is_unsupported(sto.create_dir(ObjectName::new("dir").unwrap()).await.unwrap_err()));
+        });
+    }
+
     #[test]
     fn test_fs_storage() {
         const TEST_BYTES: [u8; 4] = [0xde, 0xad, 0xfa, 0xce];
@@ -66,6 +164,486 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_memory_storage() {
+        const TEST_BYTES: [u8; 4] = [0xde, 0xad, 0xfa, 0xce];
+
+        let sto = MemoryStorage::new();
+
+        block_on(async {
+            let foo = ObjectName::new("foo").unwrap();
+            sto.write_bytes(foo, TEST_BYTES).await.unwrap();
+
+            let lst: Vec<_> = sto.list(ObjectName::empty()).await.unwrap().into_iter().collect();
+            assert_eq!(vec!["foo"], lst);
+
+            let rd_data = sto.read_bytes(foo).await.unwrap();
+            assert_eq!(TEST_BYTES, rd_data[..]);
+
+            // clones share their objects
+            let bar = ObjectName::new("bar").unwrap();
+            sto.clone().write_bytes(bar, TEST_BYTES).await.unwrap();
+            assert_eq!(2, sto.list(ObjectName::empty()).await.unwrap().len());
+
+            // missing objects and directories
+            let missing = ObjectName::new("missing").unwrap();
+            assert!(sto.read_bytes(missing).await.is_err());
+            assert!(sto.list(missing).await.is_err());
+            assert!(sto.list(foo).await.is_err());
+        });
+    }
+
+
+    fn walk_names(names: Vec<ObjectNameBuf>) -> Vec<String> {
+        let mut rv: Vec<_> = names.iter()
+            .map(|n| n.name().as_str().to_string())
+            .collect();
+        rv.sort();
+        rv
+    }
+
+    #[test]
+    fn test_fs_walk() {
+        let dir = TempDir::default();
+        std::fs::create_dir_all(dir.as_ref().join("a/b")).unwrap();
+        std::fs::write(dir.as_ref().join("top"), b"1").unwrap();
+        std::fs::write(dir.as_ref().join("a/mid"), b"2").unwrap();
+        std::fs::write(dir.as_ref().join("a/b/deep"), b"3").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", dir.as_ref().join("a/b/loop")).unwrap();
+
+        let sto = FileStorage::new(dir.as_ref());
+
+        block_on(async {
+            let flat = sto.walk(ObjectName::empty(), &WalkOptions::default()).await.unwrap();
+            assert_eq!(vec!["a", "top"], walk_names(flat));
+
+            let files = WalkOptions::new()
+                .recursive()
+                .include_dirs(false)
+                .symlinks(SymlinkPolicy::Skip);
+            let all = sto.walk(ObjectName::empty(), &files).await.unwrap();
+            assert_eq!(vec!["a/b/deep", "a/mid", "top"], walk_names(all));
+
+            let two_levels = WalkOptions::new().max_depth(2);
+            let lst = sto.walk(ObjectName::empty(), &two_levels).await.unwrap();
+            assert_eq!(vec!["a", "a/b", "a/mid", "top"], walk_names(lst));
+
+            // following the link back to 'a' must not descend forever
+            let follow = WalkOptions::new().recursive();
+            let lst = sto.walk(ObjectName::new("a").unwrap(), &follow).await.unwrap();
+            #[cfg(unix)]
+            assert_eq!(vec!["a/b", "a/b/deep", "a/b/loop", "a/mid"], walk_names(lst));
+        });
+    }
+
+    #[test]
+    fn test_list_entries() {
+        let dir = TempDir::default();
+        std::fs::create_dir(dir.as_ref().join("sub")).unwrap();
+        std::fs::write(dir.as_ref().join("file"), b"1").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("sub", dir.as_ref().join("link")).unwrap();
+
+        let sto = FileStorage::new(dir.as_ref());
+
+        block_on(async {
+            let mut entries: Vec<_> = sto.list_entries(ObjectName::empty()).await.unwrap()
+                .into_iter()
+                .map(|e| (e.name().as_str().to_string(), e.kind()))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+            assert_eq!(("file".to_string(), EntryKind::File), entries[0]);
+            #[cfg(unix)]
+            assert_eq!(("link".to_string(), EntryKind::Symlink), entries[1]);
+            assert_eq!(("sub".to_string(), EntryKind::Dir), entries[entries.len() - 1]);
+        });
+
+        let sto = MemoryStorage::new();
+
+        block_on(async {
+            sto.write_bytes(ObjectName::from_path("sub/file").unwrap(), b"1").await.unwrap();
+
+            let entries = sto.list_entries(ObjectName::empty()).await.unwrap();
+            assert_eq!(1, entries.len());
+            assert!(entries[0].is_dir());
+
+            let entries = sto.list_entries(ObjectName::new("sub").unwrap()).await.unwrap();
+            assert_eq!("sub/file", entries[0].name().as_str());
+            assert!(entries[0].is_file());
+        });
+    }
+
+    #[test]
+    fn test_memory_walk() {
+        let sto = MemoryStorage::new();
+
+        block_on(async {
+            for path in ["top", "a/mid", "a/b/deep"].iter() {
+                sto.write_bytes(ObjectName::from_path(path).unwrap(), b"x").await.unwrap();
+            }
+
+            let flat = sto.walk(ObjectName::empty(), &WalkOptions::default()).await.unwrap();
+            assert_eq!(vec!["a", "top"], walk_names(flat));
+
+            let files = WalkOptions::new().recursive().include_dirs(false);
+            let all = sto.walk(ObjectName::empty(), &files).await.unwrap();
+            assert_eq!(vec!["a/b/deep", "a/mid", "top"], walk_names(all));
+
+            let sub = sto.walk(ObjectName::new("a").unwrap(), &WalkOptions::default()).await.unwrap();
+            assert_eq!(vec!["a/b", "a/mid"], walk_names(sub));
+        });
+    }
+
+    #[test]
+    fn test_stat() {
+        const TEST_BYTES: [u8; 4] = [0xde, 0xad, 0xfa, 0xce];
+
+        let dir = TempDir::default();
+        let fs_sto = FileStorage::new(dir.as_ref());
+        let mem_sto = MemoryStorage::new();
+
+        block_on(async {
+            let foo = ObjectName::new("foo").unwrap();
+            fs_sto.write_bytes(foo, TEST_BYTES).await.unwrap();
+            mem_sto.write_bytes(foo, TEST_BYTES).await.unwrap();
+
+            let fs_meta = fs_sto.stat(foo, false).await.unwrap();
+            assert_eq!(4, fs_meta.size());
+            assert!(fs_meta.modified().is_some());
+            assert!(fs_meta.digest().is_none());
+
+            let fs_meta = fs_sto.stat(foo, true).await.unwrap();
+            let mem_meta = mem_sto.stat(foo, true).await.unwrap();
+            assert_eq!(Some(&ContentDigest::of(&TEST_BYTES)), fs_meta.digest());
+            assert_eq!(fs_meta.digest(), mem_meta.digest());
+            assert_eq!(4, mem_meta.size());
+            assert!(mem_meta.modified().is_some());
+
+            let missing = ObjectName::new("missing").unwrap();
+            assert!(fs_sto.stat(missing, false).await.is_err());
+            assert!(mem_sto.stat(missing, false).await.is_err());
+        });
+    }
+
+    async fn check_object_ops<S: AccessStorage + Sync>(sto: &S) {
+        let foo = ObjectName::new("foo").unwrap();
+        let bar = ObjectName::new("bar").unwrap();
+        let baz = ObjectName::new("baz").unwrap();
+        let dir = ObjectName::new("dir").unwrap();
+
+        sto.write_bytes(foo, b"foo").await.unwrap();
+        assert!(sto.exists(foo).await.unwrap());
+        assert!(!sto.exists(bar).await.unwrap());
+
+        // copy and rename never replace existing objects
+        sto.copy(foo, bar).await.unwrap();
+        assert_eq!(b"foo".to_vec(), sto.read_bytes(bar).await.unwrap());
+        assert!(sto.copy(foo, bar).await.unwrap_err().is_already_exists());
+        assert!(sto.rename(foo, bar).await.unwrap_err().is_already_exists());
+
+        sto.rename(bar, baz).await.unwrap();
+        assert!(!sto.exists(bar).await.unwrap());
+        assert!(sto.rename(bar, baz).await.unwrap_err().is_not_found());
+        assert!(sto.copy(bar, dir).await.unwrap_err().is_not_found());
+
+        sto.create_dir(dir).await.unwrap();
+        assert!(sto.exists(dir).await.unwrap());
+        assert!(sto.create_dir(dir).await.unwrap_err().is_already_exists());
+        let entries = sto.list_entries(ObjectName::empty()).await.unwrap();
+        assert!(entries.iter().any(|e| e.is_dir() && e.name() == dir));
+
+        let nested = ObjectName::from_path("dir/nested").unwrap();
+        sto.copy(baz, nested).await.unwrap();
+        assert!(sto.delete(dir).await.is_err());
+        sto.delete(nested).await.unwrap();
+        sto.delete(dir).await.unwrap();
+        assert!(!sto.exists(dir).await.unwrap());
+
+        sto.delete(foo).await.unwrap();
+        sto.delete(baz).await.unwrap();
+        assert!(sto.delete(foo).await.unwrap_err().is_not_found());
+        assert_eq!(0, sto.list_entries(ObjectName::empty()).await.unwrap().len());
+    }
+
+    /// Check the directory handling shared by storages with real directories
+    async fn check_dir_ops<S: AccessStorage + Sync>(sto: &S) {
+        let foo = ObjectName::new("foo").unwrap();
+        let dir = ObjectName::new("dir").unwrap();
+        let nested = ObjectName::from_path("dir/nested").unwrap();
+        let moved = ObjectName::new("moved").unwrap();
+        let moved_nested = ObjectName::from_path("moved/nested").unwrap();
+
+        // directories have metadata and are renamed with their contents
+        sto.create_dir(dir).await.unwrap();
+        sto.write_bytes(nested, b"nested").await.unwrap();
+        sto.stat(dir, false).await.unwrap();
+        sto.rename(dir, moved).await.unwrap();
+        assert!(!sto.exists(nested).await.unwrap());
+        assert_eq!(b"nested".to_vec(), sto.read_bytes(moved_nested).await.unwrap());
+
+        // objects can't be used as directories
+        let below_obj = ObjectName::from_path("foo/below").unwrap();
+        sto.write_bytes(foo, b"foo").await.unwrap();
+        assert!(sto.write_bytes(below_obj, b"foo").await.is_err());
+        assert!(sto.create_dir(below_obj).await.is_err());
+
+        sto.delete(moved_nested).await.unwrap();
+        sto.delete(moved).await.unwrap();
+        sto.delete(foo).await.unwrap();
+        assert_eq!(0, sto.list_entries(ObjectName::empty()).await.unwrap().len());
+    }
+
+    #[test]
+    fn test_object_ops() {
+        let dir = TempDir::default();
+        let sto = FileStorage::new(dir.as_ref());
+        block_on(check_object_ops(&sto));
+        block_on(check_dir_ops(&sto));
+
+        let sto = MemoryStorage::new();
+        block_on(check_object_ops(&sto));
+        block_on(check_dir_ops(&sto));
+
+        let sto = ContentAddressedStorage::new(FileStorage::new(dir.as_ref().join("cas")));
+        block_on(check_object_ops(&sto));
+        block_on(check_dir_ops(&sto));
+
+        #[cfg(feature = "sqlite")]
+        {
+            let sto = crate::SqliteStorage::open_in_memory().unwrap();
+            block_on(check_object_ops(&sto));
+            block_on(check_dir_ops(&sto));
+        }
+
+        // S3 has no real directories
+        #[cfg(feature = "s3")]
+        block_on(check_object_ops(&crate::S3Storage::new(&s3_stand_in(None), "bucket").unwrap()));
+    }
+
+    async fn check_streams<S: AccessStorage + Sync>(sto: &S) {
+        let foo = ObjectName::new("foo").unwrap();
+
+        let mut writer = sto.open_write(foo).await.unwrap();
+        writer.write_all(b"HEAD").await.unwrap();
+        writer.write_all(&[0u8; 1024]).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        // only look at the header
+        let mut reader = sto.open_read(foo).await.unwrap();
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header).await.unwrap();
+        assert_eq!(b"HEAD", &header);
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(1024, rest.len());
+
+        let missing = ObjectName::new("missing").unwrap();
+        assert!(sto.open_read(missing).await.err().unwrap().is_not_found());
+    }
+
+    #[test]
+    fn test_streams() {
+        let dir = TempDir::default();
+        let sto = FileStorage::new(dir.as_ref());
+        block_on(check_streams(&sto));
+
+        let sto = MemoryStorage::new();
+        block_on(check_streams(&sto));
+
+        let sto = ContentAddressedStorage::new(MemoryStorage::new());
+        block_on(check_streams(&sto));
+
+        #[cfg(feature = "sqlite")]
+        block_on(check_streams(&crate::SqliteStorage::open_in_memory().unwrap()));
+
+        #[cfg(feature = "s3")]
+        block_on(check_streams(&crate::S3Storage::new(&s3_stand_in(None), "bucket").unwrap()));
+    }
+
+    #[test]
+    fn test_atomic_writes() {
+        let dir = TempDir::default();
+        let sto = FileStorage::new(dir.as_ref()).sync_dir(true);
+
+        block_on(async {
+            // parent directories are created on demand
+            let nested = ObjectName::from_path("a/b/obj").unwrap();
+            sto.write_bytes(nested, b"old").await.unwrap();
+            sto.write_bytes(nested, b"new").await.unwrap();
+            assert_eq!(b"new".to_vec(), sto.read_bytes(nested).await.unwrap());
+
+            // the target only changes once the writer is shut down
+            let mut writer = sto.open_write(nested).await.unwrap();
+            writer.write_all(b"streamed").await.unwrap();
+            writer.flush().await.unwrap();
+            assert_eq!(b"new".to_vec(), sto.read_bytes(nested).await.unwrap());
+            writer.shutdown().await.unwrap();
+            drop(writer);
+            assert_eq!(b"streamed".to_vec(), sto.read_bytes(nested).await.unwrap());
+
+            // temporary files of unfinished writes are never listed
+            let abandoned = ObjectName::from_path("a/b/abandoned").unwrap();
+            let mut writer = sto.open_write(abandoned).await.unwrap();
+            writer.write_all(b"partial").await.unwrap();
+            let a_b = ObjectName::from_path("a/b").unwrap();
+            assert_eq!(vec!["a/b/obj"], sto.list(a_b).await.unwrap());
+            assert_eq!(1, sto.list_entries(a_b).await.unwrap().len());
+            let options = WalkOptions::new().recursive();
+            assert_eq!(3, sto.walk(ObjectName::empty(), &options).await.unwrap().len());
+
+            // abandoned writers leave neither target nor temporary files behind
+            drop(writer);
+            assert_eq!(1, std::fs::read_dir(dir.as_ref().join("a/b")).unwrap().count());
+        });
+
+        // replacing an object keeps its permissions
+        #[cfg(unix)]
+        block_on(async {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = dir.as_ref().join("a/b/obj");
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+            let nested = ObjectName::from_path("a/b/obj").unwrap();
+            sto.write_bytes(nested, b"private").await.unwrap();
+            assert_eq!(0o600, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+
+            let mut writer = sto.open_write(nested).await.unwrap();
+            writer.write_all(b"streamed").await.unwrap();
+            writer.shutdown().await.unwrap();
+            assert_eq!(0o600, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+        });
+
+        let sto = FileStorage::new(dir.as_ref()).create_dirs(false);
+
+        block_on(async {
+            let missing_parent = ObjectName::from_path("x/obj").unwrap();
+            assert!(sto.write_bytes(missing_parent, b"x").await.unwrap_err().is_not_found());
+        });
+    }
+
+    #[test]
+    fn test_path_confinement() {
+        let dir = TempDir::default();
+        let outside = TempDir::default();
+        std::fs::write(outside.as_ref().join("secret"), b"secret").unwrap();
+        std::fs::write(dir.as_ref().join("inside"), b"inside").unwrap();
+
+        // dot segments never make it into a name
+        assert!(matches!(ObjectName::new(".."), Err(IdxError::DotSegment(_))));
+        assert!(matches!(ObjectName::from_path("../secret"), Err(IdxError::DotSegment(_))));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(outside.as_ref(), dir.as_ref().join("out")).unwrap();
+            std::os::unix::fs::symlink(outside.as_ref().join("secret"), dir.as_ref().join("link")).unwrap();
+            std::os::unix::fs::symlink("inside", dir.as_ref().join("local_link")).unwrap();
+
+            let secret = ObjectName::from_path("out/secret").unwrap();
+            let link = ObjectName::new("link").unwrap();
+
+            // without confinement, links are followed anywhere
+            let sto = FileStorage::new(dir.as_ref());
+            block_on(async {
+                assert_eq!(b"secret".to_vec(), sto.read_bytes(secret).await.unwrap());
+                assert_eq!(b"secret".to_vec(), sto.read_bytes(link).await.unwrap());
+            });
+
+            let sto = FileStorage::new(dir.as_ref()).confine_symlinks(true);
+            block_on(async {
+                assert!(matches!(sto.read_bytes(secret).await, Err(IdxError::SymlinkEscape(_))));
+                assert!(matches!(sto.read_bytes(link).await, Err(IdxError::SymlinkEscape(_))));
+                assert!(matches!(sto.stat(link, false).await, Err(IdxError::SymlinkEscape(_))));
+
+                let new_outside = ObjectName::from_path("out/new").unwrap();
+                assert!(matches!(sto.write_bytes(new_outside, b"x").await, Err(IdxError::SymlinkEscape(_))));
+                assert!(!outside.as_ref().join("new").exists());
+
+                // links staying inside the root and new objects are fine
+                let local_link = ObjectName::new("local_link").unwrap();
+                assert_eq!(b"inside".to_vec(), sto.read_bytes(local_link).await.unwrap());
+                let nested = ObjectName::from_path("new/nested").unwrap();
+                sto.write_bytes(nested, b"x").await.unwrap();
+
+                // walking leaves out links outside the root, followed or not
+                std::os::unix::fs::symlink("new", dir.as_ref().join("local_dir")).unwrap();
+                let options = WalkOptions::new().recursive();
+                let lst = sto.walk(ObjectName::empty(), &options).await.unwrap();
+                assert_eq!(vec!["inside", "local_dir", "local_dir/nested", "local_link", "new", "new/nested"], walk_names(lst));
+                let options = options.symlinks(SymlinkPolicy::NoFollow);
+                let lst = sto.walk(ObjectName::empty(), &options).await.unwrap();
+                assert_eq!(vec!["inside", "local_dir", "local_link", "new", "new/nested"], walk_names(lst));
+
+                // so does listing
+                let mut lst = sto.list(ObjectName::empty()).await.unwrap();
+                lst.sort();
+                assert_eq!(vec!["inside", "local_dir", "local_link", "new"], lst);
+                let entries = sto.list_entries(ObjectName::empty()).await.unwrap();
+                assert_eq!(4, entries.len());
+                assert!(!entries.iter().any(|e| e.name() == link));
+
+                // the escaping link itself can be removed
+                sto.delete(link).await.unwrap();
+                assert!(outside.as_ref().join("secret").exists());
+            });
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_unicode_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TempDir::default();
+        std::fs::write(dir.as_ref().join("fine"), b"fine").unwrap();
+        std::fs::write(dir.as_ref().join("100%"), b"percent").unwrap();
+        let latin1 = dir.as_ref().join(OsStr::from_bytes(b"gr\xfc\xdf"));
+        if std::fs::write(&latin1, b"latin1").is_err() {
+            // the filesystem insists on unicode names
+            return;
+        }
+
+        block_on(async {
+            let sto = FileStorage::new(dir.as_ref());
+            assert!(sto.list(ObjectName::empty()).await.is_err());
+
+            let sto = FileStorage::new(dir.as_ref()).non_unicode(NonUnicodePolicy::Skip);
+            let mut lst = sto.list(ObjectName::empty()).await.unwrap();
+            lst.sort();
+            assert_eq!(vec!["100%", "fine"], lst);
+            assert_eq!(vec![latin1.clone()], sto.take_skipped());
+            assert!(sto.take_skipped().is_empty());
+
+            // paths nobody takes don't pile up
+            for _ in 0..FileStorage::MAX_SKIPPED + 1 {
+                sto.list(ObjectName::empty()).await.unwrap();
+            }
+            assert_eq!(FileStorage::MAX_SKIPPED, sto.take_skipped().len());
+
+            let sto = FileStorage::new(dir.as_ref()).non_unicode(NonUnicodePolicy::Escape);
+            let mut lst = sto.list(ObjectName::empty()).await.unwrap();
+            lst.sort();
+            assert_eq!(vec!["100%25", "fine", "gr%FC%DF"], lst);
+
+            // escaped names lead back to the original files
+            let escaped = ObjectName::new("gr%FC%DF").unwrap();
+            assert_eq!(b"latin1".to_vec(), sto.read_bytes(escaped).await.unwrap());
+            let percent = ObjectName::new("100%25").unwrap();
+            assert_eq!(b"percent".to_vec(), sto.read_bytes(percent).await.unwrap());
+
+            let new_name = ObjectNameBuf::escape(b"caf\xe9").unwrap();
+            sto.write_bytes(new_name.name(), b"new").await.unwrap();
+            assert!(dir.as_ref().join(OsStr::from_bytes(b"caf\xe9")).exists());
+
+            let options = WalkOptions::new().include_dirs(false);
+            let index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, index_by_name)
+                .await.unwrap();
+            assert_eq!(4, index.keys().count());
+        });
+    }
 
     #[derive(Debug,Deserialize,Serialize,PartialEq)]
     struct TestData {
@@ -92,6 +670,105 @@ mod tests {
         });
     }
 
+    async fn check_codec<C: Codec>(sto: &MemoryStorage, test_object: &TestData) {
+        let foo = ObjectName::new("foo").unwrap();
+        sto.write_as::<C,_>(foo, test_object).await.unwrap();
+
+        let rd_obj: TestData = sto.read_as::<C,_>(foo).await.unwrap();
+        assert_eq!(*test_object, rd_obj);
+    }
+
+    #[test]
+    fn test_codecs() {
+        let test_object = TestData {
+            name: String::from("Hello World"),
+            blob: vec![1, 1, -1312, 233, 585],
+        };
+
+        let sto = MemoryStorage::new();
+
+        block_on(async {
+            check_codec::<Json>(&sto, &test_object).await;
+            #[cfg(feature = "cbor")]
+            check_codec::<crate::Cbor>(&sto, &test_object).await;
+            #[cfg(feature = "msgpack")]
+            check_codec::<crate::MessagePack>(&sto, &test_object).await;
+            #[cfg(feature = "toml")]
+            check_codec::<crate::Toml>(&sto, &test_object).await;
+            #[cfg(feature = "yaml")]
+            check_codec::<crate::Yaml>(&sto, &test_object).await;
+
+            // decoding errors are reported as such
+            let bad = ObjectName::new("bad").unwrap();
+            sto.write_bytes(bad, b"\xff\xff").await.unwrap();
+            assert!(sto.read_as::<Json,TestData>(bad).await.is_err());
+            #[cfg(feature = "cbor")]
+            assert!(matches!(sto.read_as::<crate::Cbor,TestData>(bad).await, Err(IdxError::CodecError(_))));
+        });
+    }
+
+    #[test]
+    fn test_read_auto() {
+        let test_object = TestData {
+            name: String::from("Hello World"),
+            blob: vec![1, 2, 3],
+        };
+
+        let sto = MemoryStorage::new();
+
+        block_on(async {
+            let json = ObjectName::new("obj.json").unwrap();
+            sto.write_json(json, &test_object).await.unwrap();
+            let rd_obj: TestData = sto.read_auto(json).await.unwrap();
+            assert_eq!(test_object, rd_obj);
+
+            // JSON content is recognized without an extension
+            let plain = ObjectName::new("obj").unwrap();
+            sto.write_json(plain, &test_object).await.unwrap();
+            let rd_obj: TestData = sto.read_auto(plain).await.unwrap();
+            assert_eq!(test_object, rd_obj);
+
+            // generic values
+            let value: serde_json::Value = sto.read_auto(plain).await.unwrap();
+            assert_eq!("Hello World", value["name"]);
+
+            let lines = ObjectName::new("obj.jsonl").unwrap();
+            sto.write_bytes(lines, b"{\"number\": 1}\n{\"number\": 2}\n").await.unwrap();
+            let rd_objs: Vec<TestIndexData> = sto.read_auto(lines).await.unwrap();
+            assert_eq!(vec![TestIndexData { number: 1 }, TestIndexData { number: 2 }], rd_objs);
+
+            #[cfg(feature = "cbor")]
+            {
+                let cbor = ObjectName::new("obj.cbor").unwrap();
+                sto.write_as::<crate::Cbor,_>(cbor, &test_object).await.unwrap();
+                let rd_obj: TestData = sto.read_auto(cbor).await.unwrap();
+                assert_eq!(test_object, rd_obj);
+            }
+
+            #[cfg(feature = "gzip")]
+            {
+                use std::io::Write;
+
+                let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(&serde_json::to_vec(&test_object).unwrap()).unwrap();
+                let gz = ObjectName::new("obj.json.gz").unwrap();
+                sto.write_bytes(gz, encoder.finish().unwrap()).await.unwrap();
+                let rd_obj: TestData = sto.read_auto(gz).await.unwrap();
+                assert_eq!(test_object, rd_obj);
+
+                // decompression stops at the limit
+                let data = sto.read_bytes(gz).await.unwrap();
+                let res: Result<TestData,_> = crate::decode_auto_limited(gz, &data, 4);
+                assert!(matches!(res, Err(IdxError::CodecError(_))));
+            }
+
+            let unknown = ObjectName::new("obj.bin").unwrap();
+            sto.write_bytes(unknown, [0u8, 1, 2, 3]).await.unwrap();
+            let res: Result<TestData,_> = sto.read_auto(unknown).await;
+            assert!(matches!(res, Err(IdxError::CodecError(_))));
+        });
+    }
+
     #[derive(Debug,Deserialize,Serialize,PartialEq)]
     struct TestIndexData {
         number: i32
@@ -240,6 +917,1252 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_memory_indexer() {
+        const FILENAMES: [&str; 3] = [
+            "foo", "bar", "baz"
+        ];
+
+        let sto = MemoryStorage::new();
+
+        block_on(async {
+            for (i, filename) in FILENAMES.iter().enumerate() {
+                let name = ObjectName::new(filename).unwrap();
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+
+                sto.write_json(name, &obj).await.unwrap();
+            }
+
+            let number_index = HashTableIndexer::index(&sto,
+                                                       ObjectName::empty(),
+                                                       index_by_number)
+                .await.unwrap();
+
+            for (i, filename) in FILENAMES.iter().enumerate() {
+                let lkup = number_index.get(&(i as i32)).unwrap();
+                assert_eq!(vec![ObjectName::new(filename).unwrap()], lkup);
+            }
+        });
+    }
+
+    #[test]
+    fn test_cached_storage() {
+        let inner = MemoryStorage::new();
+        let sto = CachedStorage::new(inner.clone(), 64);
+        let foo = ObjectName::new("foo").unwrap();
+        let bar = ObjectName::new("bar").unwrap();
+
+        block_on(async {
+            inner.write_bytes(foo, b"cached").await.unwrap();
+            assert_eq!(b"cached".to_vec(), sto.read_bytes(foo).await.unwrap());
+            assert_eq!(vec!["foo"], sto.list(ObjectName::empty()).await.unwrap());
+
+            // changes behind the back of the cache are not seen
+            inner.write_bytes(foo, b"changed").await.unwrap();
+            inner.write_bytes(bar, b"new").await.unwrap();
+            assert_eq!(b"cached".to_vec(), sto.read_bytes(foo).await.unwrap());
+            assert_eq!(vec!["foo"], sto.list(ObjectName::empty()).await.unwrap());
+
+            // writes through the cache invalidate objects and listings
+            sto.write_bytes(foo, b"written").await.unwrap();
+            assert_eq!(b"written".to_vec(), sto.read_bytes(foo).await.unwrap());
+            assert_eq!(vec!["bar", "foo"], sto.list(ObjectName::empty()).await.unwrap());
+
+            let mut writer = sto.open_write(foo).await.unwrap();
+            writer.write_all(b"streamed").await.unwrap();
+            writer.shutdown().await.unwrap();
+            assert_eq!(b"streamed".to_vec(), sto.read_bytes(foo).await.unwrap());
+
+            sto.delete(bar).await.unwrap();
+            assert_eq!(vec!["foo"], sto.list(ObjectName::empty()).await.unwrap());
+
+            // objects are evicted once the capacity is exceeded
+            sto.clear().unwrap();
+            let big = ObjectName::new("big").unwrap();
+            inner.write_bytes(big, [0u8; 60]).await.unwrap();
+            sto.read_bytes(foo).await.unwrap();
+            sto.read_bytes(big).await.unwrap();
+            assert_eq!(60, sto.cached_bytes());
+            inner.write_bytes(foo, b"evicted").await.unwrap();
+            assert_eq!(b"evicted".to_vec(), sto.read_bytes(foo).await.unwrap());
+
+            // objects larger than the cache are not kept at all
+            inner.write_bytes(big, [0u8; 100]).await.unwrap();
+            sto.clear().unwrap();
+            sto.read_bytes(big).await.unwrap();
+            assert_eq!(0, sto.cached_bytes());
+
+            // metadata is cached and invalidated like listings
+            let sto = CachedStorage::new(inner.clone(), 1 << 10);
+            assert_eq!(7, sto.stat(foo, false).await.unwrap().size());
+            inner.write_bytes(foo, b"unseen").await.unwrap();
+            assert_eq!(7, sto.stat(foo, false).await.unwrap().size());
+            assert_eq!(6, sto.stat(foo, true).await.unwrap().size());
+            sto.write_bytes(foo, b"seen").await.unwrap();
+            assert_eq!(4, sto.stat(foo, false).await.unwrap().size());
+
+            // digests the wrapped storage knows are passed on and cached
+            let inner = ContentAddressedStorage::new(MemoryStorage::new());
+            let sto = CachedStorage::new(inner.clone(), 1 << 10);
+            inner.write_bytes(foo, b"digest").await.unwrap();
+            assert_eq!(Some(ContentDigest::of(b"digest")), sto.content_digest(foo).await.unwrap());
+            inner.write_bytes(foo, b"unseen").await.unwrap();
+            assert_eq!(Some(ContentDigest::of(b"digest")), sto.content_digest(foo).await.unwrap());
+            sto.write_bytes(foo, b"seen").await.unwrap();
+            assert_eq!(Some(ContentDigest::of(b"seen")), sto.content_digest(foo).await.unwrap());
+        });
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    async fn check_compressed(compression: crate::Compression, magic: &[u8]) {
+        let inner = MemoryStorage::new();
+        let sto = crate::CompressedStorage::new(inner.clone(), compression);
+        let suffix = compression.extension();
+
+        for (i, filename) in ["foo", "bar"].iter().enumerate() {
+            let obj = TestIndexData {
+                number: i as i32
+            };
+            sto.write_json(ObjectName::new(filename).unwrap(), &obj).await.unwrap();
+        }
+        sto.create_dir(ObjectName::new("dir").unwrap()).await.unwrap();
+        inner.write_bytes(ObjectName::new("plain").unwrap(), b"not compressed").await.unwrap();
+
+        let stored_name = format!("foo{}", suffix);
+        let stored = inner.read_bytes(ObjectName::new(&stored_name).unwrap()).await.unwrap();
+        assert!(stored.starts_with(magic));
+
+        let mut listing = sto.list(ObjectName::empty()).await.unwrap();
+        listing.sort();
+        assert_eq!(vec!["bar", "dir", "foo"], listing);
+
+        // objects stored without the suffix are out of reach, directories aren't
+        let plain = ObjectName::new("plain").unwrap();
+        assert!(!sto.exists(plain).await.unwrap());
+        assert!(sto.delete(plain).await.unwrap_err().is_not_found());
+        assert!(sto.rename(plain, ObjectName::new("other").unwrap()).await.unwrap_err().is_not_found());
+        assert!(inner.exists(plain).await.unwrap());
+        let dir = ObjectName::new("dir").unwrap();
+        assert!(sto.exists(dir).await.unwrap());
+        sto.stat(dir, false).await.unwrap();
+        let moved_dir = ObjectName::new("moved_dir").unwrap();
+        sto.rename(dir, moved_dir).await.unwrap();
+        sto.delete(moved_dir).await.unwrap();
+        assert!(!inner.exists(moved_dir).await.unwrap());
+
+        let number_index = HashTableIndexer::index(&sto, ObjectName::empty(), index_by_number)
+            .await.unwrap();
+        assert_eq!(vec![ObjectName::new("bar").unwrap()], number_index.get(&1).unwrap());
+
+        let name = ObjectName::new("streamed").unwrap();
+        let mut writer = sto.open_write(name).await.unwrap();
+        writer.write_all(b"hello ").await.unwrap();
+        writer.write_all(b"compression").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert_eq!(b"hello compression".to_vec(), sto.read_bytes(name).await.unwrap());
+        assert_eq!(17, sto.stat(name, false).await.unwrap().size());
+
+        // objects expanding beyond the limit aren't read
+        let limited = crate::CompressedStorage::new(inner.clone(), compression).max_size(16);
+        assert!(matches!(limited.read_bytes(name).await, Err(IdxError::CodecError(_))));
+        let limited = limited.max_size(17);
+        assert_eq!(b"hello compression".to_vec(), limited.read_bytes(name).await.unwrap());
+
+        let moved = ObjectName::new("moved").unwrap();
+        sto.rename(name, moved).await.unwrap();
+        assert!(!sto.exists(name).await.unwrap());
+        assert!(sto.exists(moved).await.unwrap());
+        sto.delete(moved).await.unwrap();
+        assert!(!sto.exists(moved).await.unwrap());
+
+        // without a suffix, names are passed through unchanged
+        let sto = crate::CompressedStorage::new(MemoryStorage::new(), compression).suffix("");
+        sto.write_bytes(name, b"unchanged").await.unwrap();
+        assert_eq!(vec!["streamed"], sto.list(ObjectName::empty()).await.unwrap());
+        assert_eq!(b"unchanged".to_vec(), sto.read_bytes(name).await.unwrap());
+    }
+
+    #[test]
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn test_compressed_storage() {
+        block_on(async {
+            #[cfg(feature = "gzip")]
+            check_compressed(crate::Compression::Gzip, &[0x1f, 0x8b]).await;
+            #[cfg(feature = "zstd")]
+            check_compressed(crate::Compression::Zstd, &[0x28, 0xb5, 0x2f, 0xfd]).await;
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "encryption")]
+    fn test_encrypted_storage() {
+        let inner = MemoryStorage::new();
+        let key = crate::EncryptedStorage::<MemoryStorage>::generate_key();
+        let sto = crate::EncryptedStorage::new(inner.clone(), &key);
+
+        block_on(async {
+            for (i, filename) in ["foo", "bar", "baz"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::new(filename).unwrap(), &obj).await.unwrap();
+            }
+
+            let foo = ObjectName::new("foo").unwrap();
+            let stored = inner.read_bytes(foo).await.unwrap();
+            assert!(!stored.windows(6).any(|w| w == b"number"));
+            assert_eq!(b"{\"number\":0}".to_vec(), sto.read_bytes(foo).await.unwrap());
+            assert_eq!(12, sto.stat(foo, false).await.unwrap().size());
+
+            let number_index = HashTableIndexer::index(&sto, ObjectName::empty(), index_by_number)
+                .await.unwrap();
+            assert_eq!(vec![ObjectName::new("bar").unwrap()], number_index.get(&1).unwrap());
+
+            let name = ObjectName::new("streamed").unwrap();
+            let mut writer = sto.open_write(name).await.unwrap();
+            writer.write_all(b"secret").await.unwrap();
+            writer.shutdown().await.unwrap();
+            assert_eq!(b"secret".to_vec(), sto.read_bytes(name).await.unwrap());
+
+            // the same contents never produce the same ciphertext
+            sto.write_bytes(name, b"secret").await.unwrap();
+            assert_ne!(stored, inner.read_bytes(name).await.unwrap());
+
+            let mut modified = stored.clone();
+            let last = modified.len() - 1;
+            modified[last] ^= 1;
+            inner.write_bytes(foo, modified).await.unwrap();
+            assert!(matches!(sto.read_bytes(foo).await, Err(IdxError::Tampered(_))));
+
+            inner.write_bytes(foo, b"short").await.unwrap();
+            assert!(matches!(sto.read_bytes(foo).await, Err(IdxError::Tampered(_))));
+
+            // objects are bound to their names
+            let bar = ObjectName::new("bar").unwrap();
+            let baz = ObjectName::new("baz").unwrap();
+            inner.write_bytes(baz, inner.read_bytes(bar).await.unwrap()).await.unwrap();
+            assert!(matches!(sto.read_bytes(baz).await, Err(IdxError::Tampered(_))));
+
+            // so renaming and copying encrypts them again
+            let copied = ObjectName::from_path("sub/copied").unwrap();
+            sto.copy(bar, copied).await.unwrap();
+            sto.rename(ObjectName::new("sub").unwrap(), ObjectName::new("moved").unwrap()).await.unwrap();
+            let moved = ObjectName::from_path("moved/copied").unwrap();
+            assert_eq!(b"{\"number\":1}".to_vec(), sto.read_bytes(moved).await.unwrap());
+            assert!(!sto.exists(ObjectName::new("sub").unwrap()).await.unwrap());
+            assert!(sto.rename(bar, ObjectName::new("moved").unwrap()).await.unwrap_err().is_already_exists());
+
+            let other_key = crate::EncryptedStorage::<MemoryStorage>::generate_key();
+            let other = crate::EncryptedStorage::new(inner.clone(), &other_key);
+            assert!(matches!(other.read_bytes(name).await, Err(IdxError::Tampered(_))));
+        });
+    }
+
+    #[cfg(feature = "archive")]
+    const ARCHIVE_MEMBERS: [(&str, &[u8]); 3] = [
+        ("foo", b"{\"number\": 0}"),
+        ("./bar", b"{\"number\": 1}"),
+        ("sub/baz", b"{\"number\": 2}"),
+    ];
+
+    #[cfg(feature = "archive")]
+    async fn check_archive<S>(sto: S)
+        where
+            S: AccessStorage + Clone + Send + Sync + 'static
+    {
+        let mut listing: Vec<_> = sto.list(ObjectName::empty()).await.unwrap().into_iter().collect();
+        listing.sort();
+        assert_eq!(vec!["bar", "empty", "foo", "sub"], listing);
+
+        let options = WalkOptions::new().recursive().include_dirs(false);
+        let number_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, index_by_number)
+            .await.unwrap();
+        assert_eq!(vec![ObjectName::from_path("sub/baz").unwrap()], number_index.get(&2).unwrap());
+        assert_eq!(3, number_index.keys().count());
+
+        let foo = ObjectName::new("foo").unwrap();
+        assert_eq!(13, sto.stat(foo, false).await.unwrap().size());
+        assert!(sto.exists(ObjectName::new("empty").unwrap()).await.unwrap());
+        assert!(sto.read_bytes(ObjectName::new("missing").unwrap()).await.unwrap_err().is_not_found());
+
+        assert!(matches!(sto.write_bytes(foo, b"x").await, Err(IdxError::ReadOnly(_))));
+        assert!(matches!(sto.open_write(foo).await, Err(IdxError::ReadOnly(_))));
+        assert!(matches!(sto.delete(foo).await, Err(IdxError::ReadOnly(_))));
+        assert!(matches!(sto.create_dir(ObjectName::new("new").unwrap()).await, Err(IdxError::ReadOnly(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "archive")]
+    fn test_archive_storage() {
+        use std::io::Write;
+
+        let dir = TempDir::default();
+
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in ARCHIVE_MEMBERS.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, "empty/", std::io::empty()).unwrap();
+        let tar_data = builder.into_inner().unwrap();
+
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&tar_data).unwrap();
+        std::fs::write(dir.as_ref().join("test.tar.gz"), encoder.finish().unwrap()).unwrap();
+
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(dir.as_ref().join("test.zip")).unwrap());
+        for (path, data) in ARCHIVE_MEMBERS.iter() {
+            writer.start_file(*path, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.add_directory("empty/", zip::write::FileOptions::default()).unwrap();
+        writer.finish().unwrap();
+
+        block_on(async {
+            check_archive(crate::TarStorage::from_bytes(&tar_data).unwrap()).await;
+            check_archive(crate::TarStorage::open(dir.as_ref().join("test.tar.gz")).await.unwrap()).await;
+            check_archive(crate::ZipStorage::open(dir.as_ref().join("test.zip")).await.unwrap()).await;
+        });
+    }
+
+    #[test]
+    fn test_overlay_storage() {
+        let upper = MemoryStorage::new();
+        let lower = MemoryStorage::new();
+
+        block_on(async {
+            for (i, path) in ["a", "b", "sub/c"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                lower.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+            upper.write_json(ObjectName::new("b").unwrap(), &TestIndexData { number: 10 }).await.unwrap();
+            upper.write_json(ObjectName::new("d").unwrap(), &TestIndexData { number: 3 }).await.unwrap();
+
+            let sto = OverlayStorage::new(upper.clone(), lower.clone());
+            let a = ObjectName::new("a").unwrap();
+            let b = ObjectName::new("b").unwrap();
+
+            assert_eq!(vec!["a", "b", "d", "sub"], sto.list(ObjectName::empty()).await.unwrap());
+            assert_eq!(TestIndexData { number: 10 }, *sto.read_json(b).await.unwrap());
+            assert_eq!(TestIndexData { number: 0 }, *sto.read_json(a).await.unwrap());
+
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let number_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, index_by_number)
+                .await.unwrap();
+            assert_eq!(vec![ObjectName::from_path("sub/c").unwrap()], number_index.get(&2).unwrap());
+            assert!(number_index.get(&1).unwrap().is_empty());
+
+            // changes only go to the upper layer
+            let e = ObjectName::new("e").unwrap();
+            sto.write_bytes(e, b"new").await.unwrap();
+            assert!(upper.exists(e).await.unwrap());
+            assert!(!lower.exists(e).await.unwrap());
+
+            assert!(matches!(sto.delete(a).await, Err(IdxError::ReadOnly(_))));
+            assert!(matches!(sto.rename(a, ObjectName::new("f").unwrap()).await, Err(IdxError::ReadOnly(_))));
+            sto.delete(e).await.unwrap();
+            assert!(!sto.exists(e).await.unwrap());
+
+            // whiteouts hide objects and directories of the lower layer
+            let sto = sto.whiteouts(true);
+            sto.delete(a).await.unwrap();
+            assert!(!sto.exists(a).await.unwrap());
+            assert!(sto.read_bytes(a).await.unwrap_err().is_not_found());
+            assert!(lower.exists(a).await.unwrap());
+            assert_eq!(vec!["b", "d", "sub"], sto.list(ObjectName::empty()).await.unwrap());
+
+            sto.delete(b).await.unwrap();
+            assert!(!sto.exists(b).await.unwrap());
+
+            let sub = ObjectName::new("sub").unwrap();
+            assert!(sto.delete(sub).await.is_err());
+            sto.delete(ObjectName::from_path("sub/c").unwrap()).await.unwrap();
+            sto.delete(sub).await.unwrap();
+            assert_eq!(vec!["d"], sto.list(ObjectName::empty()).await.unwrap());
+
+            // writing a deleted object makes it visible again
+            sto.write_bytes(a, b"again").await.unwrap();
+            assert_eq!(b"again".to_vec(), sto.read_bytes(a).await.unwrap());
+            assert_eq!(vec!["a", "d"], sto.list(ObjectName::empty()).await.unwrap());
+
+            let f = ObjectName::new("f").unwrap();
+            sto.rename(ObjectName::new("d").unwrap(), f).await.unwrap();
+            assert_eq!(vec!["a", "f"], sto.list(ObjectName::empty()).await.unwrap());
+
+            // names of whiteouts can't be written
+            let marker = ObjectName::from_path("sub/.wh.g").unwrap();
+            assert!(sto.write_bytes(marker, b"x").await.is_err());
+            assert!(sto.open_write(marker).await.is_err());
+            assert!(sto.create_dir(marker).await.is_err());
+            assert!(sto.copy(a, marker).await.is_err());
+            assert!(sto.rename(a, marker).await.is_err());
+            assert!(!upper.exists(marker).await.unwrap());
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "git")]
+    fn test_git_storage() {
+        let dir = TempDir::default();
+        git2::Repository::init(dir.as_ref()).unwrap();
+        let sto = crate::GitStorage::on_branch(dir.as_ref(), "main").unwrap()
+            .author("Test", "test@example.com");
+
+        block_on(async {
+            assert!(sto.list(ObjectName::empty()).await.unwrap().is_empty());
+            assert!(sto.commit_id().await.unwrap().is_none());
+
+            for (i, path) in ["foo", "bar", "sub/baz"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+            let foo = ObjectName::new("foo").unwrap();
+            sto.write_json(foo, &TestIndexData { number: 10 }).await.unwrap();
+
+            assert_eq!(vec!["bar", "foo", "sub"], sto.list(ObjectName::empty()).await.unwrap());
+            assert_eq!(TestIndexData { number: 10 }, *sto.read_json(foo).await.unwrap());
+
+            // earlier states stay available
+            let old = crate::GitStorage::at_revision(dir.as_ref(), "main~1").unwrap();
+            assert_eq!(TestIndexData { number: 0 }, *old.read_json(foo).await.unwrap());
+            assert_ne!(sto.commit_id().await.unwrap(), old.commit_id().await.unwrap());
+            assert!(matches!(old.write_bytes(foo, b"x").await, Err(IdxError::ReadOnly(_))));
+
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let new_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, index_by_number)
+                .await.unwrap();
+            let old_index = HashTableIndexer::index_with(&old, ObjectName::empty(), &options, index_by_number)
+                .await.unwrap();
+            assert_eq!(vec![foo], new_index.get(&10).unwrap());
+            assert_eq!(vec![foo], old_index.get(&0).unwrap());
+            assert_eq!(vec![ObjectName::from_path("sub/baz").unwrap()], new_index.get(&2).unwrap());
+
+            let mut writer = sto.open_write(ObjectName::new("streamed").unwrap()).await.unwrap();
+            writer.write_all(b"committed").await.unwrap();
+            writer.shutdown().await.unwrap();
+            assert_eq!(b"committed".to_vec(), sto.read_bytes(ObjectName::new("streamed").unwrap()).await.unwrap());
+
+            let sub = ObjectName::new("sub").unwrap();
+            let baz = ObjectName::from_path("sub/baz").unwrap();
+            let moved = ObjectName::new("moved").unwrap();
+            assert!(sto.rename(foo, ObjectName::new("bar").unwrap()).await.unwrap_err().is_already_exists());
+            sto.rename(baz, moved).await.unwrap();
+            sto.copy(moved, baz).await.unwrap();
+            assert!(sto.delete(sub).await.is_err());
+            sto.delete(baz).await.unwrap();
+            assert!(!sto.exists(sub).await.unwrap());
+            assert!(sto.read_bytes(baz).await.unwrap_err().is_not_found());
+            assert!(sto.create_dir(sub).await.is_err());
+            assert_eq!(vec!["bar", "foo", "moved", "streamed"], sto.list(ObjectName::empty()).await.unwrap());
+            assert_eq!(12, sto.stat(moved, false).await.unwrap().size());
+
+            // entries git allows but objects can't be named after are left out
+            let repo = git2::Repository::open(dir.as_ref()).unwrap();
+            let parent = repo.find_reference("refs/heads/main").unwrap().peel_to_commit().unwrap();
+            let mut builder = repo.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+            builder.insert("back\\slash", repo.blob(b"x").unwrap(), 0o100644).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+            repo.commit(Some("refs/heads/main"), &signature, &signature, "Add backslash", &tree, &[&parent]).unwrap();
+            assert_eq!(vec!["bar", "foo", "moved", "streamed"], sto.list(ObjectName::empty()).await.unwrap());
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "sqlite")]
+    fn test_sqlite_storage() {
+        let dir = TempDir::default();
+        let db_path = dir.as_ref().join("objects.db");
+        let sto = crate::SqliteStorage::open(&db_path).unwrap();
+
+        block_on(async {
+            for (i, path) in ["one", "sub/two", "sub/subsub/three", "sub0"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+
+            let sub = ObjectName::new("sub").unwrap();
+            assert_eq!(vec!["one", "sub", "sub0"], sto.list(ObjectName::empty()).await.unwrap());
+            assert_eq!(vec!["sub/subsub", "sub/two"], sto.list(sub).await.unwrap());
+            assert!(sto.list(ObjectName::new("one").unwrap()).await.is_err());
+            assert!(sto.list(ObjectName::new("missing").unwrap()).await.unwrap_err().is_not_found());
+
+            let two = ObjectName::from_path("sub/two").unwrap();
+            assert!(sto.stat(two, false).await.unwrap().modified().is_some());
+            assert!(sto.write_bytes(sub, b"x").await.is_err());
+            assert!(sto.copy(two, ObjectName::from_path("missing/two").unwrap()).await.unwrap_err().is_not_found());
+
+            // directories move with their contents
+            let moved = ObjectName::new("moved").unwrap();
+            assert!(sto.rename(sub, ObjectName::from_path("sub/subsub/inside").unwrap()).await.is_err());
+            sto.rename(sub, moved).await.unwrap();
+            assert!(!sto.exists(sub).await.unwrap());
+            assert_eq!(vec!["moved/subsub", "moved/two"], sto.list(moved).await.unwrap());
+        });
+        drop(sto);
+
+        // objects persist in the database file
+        let sto = crate::SqliteStorage::open(&db_path).unwrap();
+        block_on(async {
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let number_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, index_by_number)
+                .await.unwrap();
+            assert_eq!(vec![ObjectName::from_path("moved/subsub/three").unwrap()], number_index.get(&2).unwrap());
+            assert_eq!(4, number_index.keys().count());
+        });
+    }
+
+    /// Start a server on a background thread speaking just enough of the S3
+    /// API for `S3Storage`, and return its endpoint
+    ///
+    /// Requests have to be signed with `access_key_id` if one is given.
+    #[cfg(feature = "s3")]
+    fn s3_stand_in(access_key_id: Option<&'static str>) -> String {
+        use hyper::{Body,Method,Request,Response,Server,StatusCode};
+        use hyper::service::{make_service_fn,service_fn};
+        use percent_encoding::percent_decode_str;
+        use std::collections::{BTreeMap,HashMap};
+        use std::sync::{Arc,Mutex};
+
+        type Bucket = Arc<Mutex<BTreeMap<String,Vec<u8>>>>;
+
+        fn decode(s: &str) -> String {
+            percent_decode_str(s).decode_utf8_lossy().into_owned()
+        }
+
+        fn escape(s: &str) -> String {
+            s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+        }
+
+        fn error(status: StatusCode, code: &str) -> Response<Body> {
+            let body = format!("<Error><Code>{}</Code><Message>Stand-in error</Message></Error>", code);
+            Response::builder().status(status).body(Body::from(body)).unwrap()
+        }
+
+        fn list(objects: &BTreeMap<String,Vec<u8>>, query: &HashMap<String,String>) -> Response<Body> {
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let delimit = query.contains_key("delimiter");
+            let max_keys = query.get("max-keys").map_or(1000, |n| n.parse().unwrap());
+            let start = query.get("continuation-token").cloned().unwrap_or_default();
+
+            // keys and common prefixes in order, each once
+            let mut items: Vec<(String,bool)> = vec![];
+            for key in objects.keys().filter(|key| key.starts_with(&prefix)) {
+                let item = match key[prefix.len()..].find('/') {
+                    Some(pos) if delimit => (key[..prefix.len() + pos + 1].to_string(), true),
+                    _ => (key.clone(), false),
+                };
+                if items.last() != Some(&item) && item.0 > start {
+                    items.push(item);
+                }
+            }
+
+            let truncated = items.len() > max_keys;
+            items.truncate(max_keys);
+            let mut body = format!("<ListBucketResult><IsTruncated>{}</IsTruncated>", truncated);
+            if truncated {
+                body += &format!("<NextContinuationToken>{}</NextContinuationToken>", escape(&items.last().unwrap().0));
+            }
+            for (item, is_prefix) in items {
+                if is_prefix {
+                    body += &format!("<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>", escape(&item));
+                } else {
+                    body += &format!("<Contents><Key>{}</Key></Contents>", escape(&item));
+                }
+            }
+            body += "</ListBucketResult>";
+            Response::new(Body::from(body))
+        }
+
+        async fn handle(req: Request<Body>, bucket: Bucket, access_key_id: Option<&str>) -> Response<Body> {
+            if let Some(access_key_id) = access_key_id {
+                let credential = format!("AWS4-HMAC-SHA256 Credential={}/", access_key_id);
+                let authorized = req.headers().get("authorization")
+                    .is_some_and(|auth| auth.to_str().unwrap().starts_with(&credential));
+                if !authorized {
+                    return error(StatusCode::FORBIDDEN, "AccessDenied");
+                }
+            }
+
+            let path = decode(req.uri().path());
+            let key = match path.trim_start_matches('/').split_once('/') {
+                Some(("bucket", key)) => key.to_string(),
+                None if path == "/bucket" => {
+                    let query = req.uri().query().unwrap_or_default().split('&')
+                        .filter_map(|pair| pair.split_once('='))
+                        .map(|(k, v)| (decode(k), decode(v)))
+                        .collect();
+                    return list(&bucket.lock().unwrap(), &query);
+                }
+                _ => return error(StatusCode::NOT_FOUND, "NoSuchBucket"),
+            };
+
+            let method = req.method().clone();
+            let copy_source = req.headers().get("x-amz-copy-source")
+                .map(|source| decode(source.to_str().unwrap()));
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+
+            let mut objects = bucket.lock().unwrap();
+            match method {
+                Method::GET | Method::HEAD => match objects.get(&key) {
+                    Some(data) if method == Method::HEAD => Response::builder()
+                        .header("content-length", data.len())
+                        .header("last-modified", httpdate::fmt_http_date(std::time::SystemTime::now()))
+                        .body(Body::empty()).unwrap(),
+                    Some(data) => Response::new(Body::from(data.clone())),
+                    None => error(StatusCode::NOT_FOUND, "NoSuchKey"),
+                },
+                Method::PUT => {
+                    let data = match copy_source {
+                        Some(source) => match objects.get(source.trim_start_matches("/bucket/")) {
+                            Some(data) => data.clone(),
+                            None => return error(StatusCode::NOT_FOUND, "NoSuchKey"),
+                        },
+                        None => body.to_vec(),
+                    };
+                    objects.insert(key, data);
+                    Response::new(Body::empty())
+                }
+                Method::DELETE => {
+                    objects.remove(&key);
+                    Response::builder().status(StatusCode::NO_CONTENT).body(Body::empty()).unwrap()
+                }
+                _ => error(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed"),
+            }
+        }
+
+        let bucket = Bucket::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let bucket = bucket.clone();
+                    async move {
+                        Ok::<_, hyper::Error>(service_fn(move |req| {
+                            let resp = handle(req, bucket.clone(), access_key_id);
+                            async move { Ok::<_, hyper::Error>(resp.await) }
+                        }))
+                    }
+                });
+                let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+                tx.send(server.local_addr()).unwrap();
+                server.await.unwrap();
+            });
+        });
+
+        format!("http://{}", rx.recv().unwrap())
+    }
+
+    #[test]
+    #[cfg(feature = "s3")]
+    fn test_s3_storage() {
+        let endpoint = s3_stand_in(Some("test-key"));
+        let sto = crate::S3Storage::new(&endpoint, "bucket").unwrap()
+            .credentials("test-key", "secret")
+            .prefix("data")
+            .page_size(2);
+
+        block_on(async {
+            for (i, path) in ["one", "two", "sub/three", "sub/four", "sub/deeper/five"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+
+            // listings span several pages
+            let sub = ObjectName::new("sub").unwrap();
+            assert_eq!(vec!["one", "sub", "two"], sto.list(ObjectName::empty()).await.unwrap());
+            assert_eq!(vec!["sub/deeper", "sub/four", "sub/three"], sto.list(sub).await.unwrap());
+            assert!(sto.list(ObjectName::new("one").unwrap()).await.is_err());
+            assert!(sto.list(ObjectName::new("missing").unwrap()).await.unwrap_err().is_not_found());
+
+            let metadata = sto.stat(ObjectName::new("one").unwrap(), true).await.unwrap();
+            assert_eq!(12, metadata.size());
+            assert!(metadata.modified().is_some());
+            assert_eq!(Some(&ContentDigest::of(br#"{"number":0}"#)), metadata.digest());
+
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let number_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, index_by_number)
+                .await.unwrap();
+            assert_eq!(vec![ObjectName::from_path("sub/deeper/five").unwrap()], number_index.get(&4).unwrap());
+            assert_eq!(5, number_index.keys().count());
+
+            // keys outside the prefix are not visible
+            let root = crate::S3Storage::new(&endpoint, "bucket").unwrap()
+                .credentials("test-key", "secret");
+            assert_eq!(vec!["data"], root.list(ObjectName::empty()).await.unwrap());
+
+            // keys that aren't valid object names are left out
+            let dotted = crate::S3Storage::new(&endpoint, "bucket").unwrap()
+                .credentials("test-key", "secret")
+                .prefix("data/..");
+            dotted.write_bytes(ObjectName::new("odd").unwrap(), b"odd").await.unwrap();
+            assert_eq!(vec!["one", "sub", "two"], sto.list(ObjectName::empty()).await.unwrap());
+            assert_eq!(5, sto.walk(ObjectName::empty(), &options).await.unwrap().len());
+
+            let anonymous = crate::S3Storage::new(&endpoint, "bucket").unwrap();
+            let err = anonymous.read_bytes(ObjectName::new("data").unwrap()).await.unwrap_err();
+            assert!(err.to_string().contains("AccessDenied"));
+        });
+    }
+
+    /// Start a static file server on a background thread, and return its URL
+    ///
+    /// Directories are listed as HTML pages, or as JSON like nginx does if
+    /// `json_index` is set.
+    #[cfg(feature = "http")]
+    fn http_stand_in(files: Vec<(&'static str, Vec<u8>)>, json_index: bool) -> String {
+        use hyper::{Body,Request,Response,Server,StatusCode};
+        use hyper::service::{make_service_fn,service_fn};
+        use percent_encoding::percent_decode_str;
+        use std::collections::BTreeMap;
+        use std::sync::Arc;
+
+        fn index_page(files: &BTreeMap<String,Vec<u8>>, dir: &str, json_index: bool) -> Option<Response<Body>> {
+            let mut entries = BTreeMap::new();
+            for rest in files.keys().filter_map(|path| path.strip_prefix(dir)) {
+                match rest.find('/') {
+                    Some(pos) => entries.insert(&rest[..pos], true),
+                    None => entries.insert(rest, false),
+                };
+            }
+            if entries.is_empty() {
+                return None;
+            }
+
+            let resp = if json_index {
+                let entries: Vec<_> = entries.iter()
+                    .map(|(name, is_dir)| serde_json::json!({
+                        "name": name,
+                        "type": if *is_dir { "directory" } else { "file" },
+                    }))
+                    .collect();
+                Response::builder()
+                    .header("content-type", "application/json")
+                    .body(Body::from(serde_json::to_vec(&entries).unwrap()))
+            } else {
+                let mut page = format!("<html><body><h1>Index of /{}</h1><a href=\"../\">../</a>\n", dir);
+                for (name, is_dir) in entries {
+                    let slash = if is_dir { "/" } else { "" };
+                    page += &format!("<a href=\"{}{}\">{}{}</a>\n", name.replace(' ', "%20"), slash, name, slash);
+                }
+                page += "</body></html>";
+                Response::builder()
+                    .header("content-type", "text/html")
+                    .body(Body::from(page))
+            };
+            Some(resp.unwrap())
+        }
+
+        fn handle(req: Request<Body>, files: &BTreeMap<String,Vec<u8>>, json_index: bool) -> Response<Body> {
+            let path = percent_decode_str(req.uri().path()).decode_utf8_lossy();
+            let path = path.trim_start_matches('/');
+
+            let resp = if path.is_empty() || path.ends_with('/') {
+                index_page(files, path, json_index)
+            } else if let Some(target) = path.strip_prefix("redirect/") {
+                Some(Response::builder()
+                    .status(StatusCode::FOUND)
+                    .header("location", format!("/{}", target))
+                    .body(Body::empty())
+                    .unwrap())
+            } else if let Some(data) = files.get(path) {
+                Some(Response::builder()
+                    .header("content-length", data.len())
+                    .header("last-modified", httpdate::fmt_http_date(std::time::SystemTime::now()))
+                    .body(Body::from(data.clone()))
+                    .unwrap())
+            } else if files.keys().any(|file| file.starts_with(&format!("{}/", path))) {
+                Some(Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header("location", format!("/{}/", path))
+                    .body(Body::empty())
+                    .unwrap())
+            } else {
+                None
+            };
+
+            resp.unwrap_or_else(|| Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap())
+        }
+
+        let files: Arc<BTreeMap<_,_>> = Arc::new(files.into_iter()
+            .map(|(path, data)| (path.to_string(), data))
+            .collect());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let make_service = make_service_fn(move |_| {
+                    let files = files.clone();
+                    async move {
+                        Ok::<_, hyper::Error>(service_fn(move |req| {
+                            let resp = handle(req, &files, json_index);
+                            async move { Ok::<_, hyper::Error>(resp) }
+                        }))
+                    }
+                });
+                let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+                tx.send(server.local_addr()).unwrap();
+                server.await.unwrap();
+            });
+        });
+
+        format!("http://{}", rx.recv().unwrap())
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn test_http_storage() {
+        let mut files = vec![];
+        for (i, path) in ["one", "with space", "sub/two", "sub/deeper/three"].iter().enumerate() {
+            let obj = TestIndexData {
+                number: i as i32
+            };
+            files.push((*path, serde_json::to_vec(&obj).unwrap()));
+        }
+        // paths that aren't valid object names are left out of listings
+        let paths: Vec<_> = files.iter().map(|(path, _)| *path).chain(["sub//odd"]).collect();
+        let manifest = serde_json::to_vec(&paths).unwrap();
+        files.push(("manifest.json", manifest));
+
+        let html = crate::HttpStorage::new(&http_stand_in(files.clone(), false)).unwrap();
+        let json = crate::HttpStorage::new(&(http_stand_in(files.clone(), true) + "/")).unwrap();
+        let manifest = crate::HttpStorage::new(&http_stand_in(files, false)).unwrap()
+            .manifest("manifest.json");
+
+        block_on(async {
+            let sub = ObjectName::new("sub").unwrap();
+            let one = ObjectName::new("one").unwrap();
+
+            // the manifest doesn't list itself
+            let with_manifest = ["manifest.json", "one", "sub", "with space"];
+            for (sto, root) in [(&html, &with_manifest[..]), (&json, &with_manifest[..]), (&manifest, &with_manifest[1..])].iter() {
+                assert_eq!(root.to_vec(), sto.list(ObjectName::empty()).await.unwrap());
+                assert_eq!(vec!["sub/deeper", "sub/two"], sto.list(sub).await.unwrap());
+                assert!(sto.list(ObjectName::new("missing").unwrap()).await.unwrap_err().is_not_found());
+
+                let space = ObjectName::new("with space").unwrap();
+                assert_eq!(br#"{"number":1}"#.to_vec(), sto.read_bytes(space).await.unwrap());
+                assert!(sto.read_bytes(ObjectName::new("missing").unwrap()).await.unwrap_err().is_not_found());
+                assert!(sto.exists(sub).await.unwrap());
+                assert!(!sto.exists(ObjectName::new("missing").unwrap()).await.unwrap());
+                assert_eq!(12, sto.stat(one, false).await.unwrap().size());
+
+                // redirects of objects are followed, those of directories aren't
+                let redirected = ObjectName::from_path("redirect/one").unwrap();
+                assert_eq!(br#"{"number":0}"#.to_vec(), sto.read_bytes(redirected).await.unwrap());
+                assert_eq!(12, sto.stat(redirected, false).await.unwrap().size());
+                assert!(sto.read_bytes(sub).await.unwrap_err().is_not_found());
+
+                let options = WalkOptions::new().recursive().include_dirs(false);
+                let number_index = HashTableIndexer::index_with(*sto, sub, &options, index_by_number)
+                    .await.unwrap();
+                assert_eq!(vec![ObjectName::from_path("sub/deeper/three").unwrap()], number_index.get(&3).unwrap());
+                assert_eq!(2, number_index.keys().count());
+
+                assert!(matches!(sto.write_bytes(one, b"1").await, Err(IdxError::ReadOnly(_))));
+                assert!(matches!(sto.delete(one).await, Err(IdxError::ReadOnly(_))));
+            }
+        });
+    }
+
+    #[test]
+    fn test_content_addressed_storage() {
+        let sto = ContentAddressedStorage::new(MemoryStorage::new());
+        let blobs = ObjectName::new("blobs").unwrap();
+        let all = WalkOptions::new().recursive().include_dirs(false);
+
+        block_on(async {
+            let a = ObjectName::new("a").unwrap();
+            let b = ObjectName::from_path("sub/b").unwrap();
+            let c = ObjectName::new("c").unwrap();
+            sto.write_bytes(a, b"same").await.unwrap();
+            sto.write_bytes(b, b"same").await.unwrap();
+            sto.write_bytes(c, b"different").await.unwrap();
+
+            // identical contents are stored once
+            assert_eq!(2, sto.inner().walk(blobs, &all).await.unwrap().len());
+            assert_eq!(ContentDigest::of(b"same"), sto.digest(a).await.unwrap());
+            assert_eq!(sto.digest(a).await.unwrap(), sto.digest(b).await.unwrap());
+            assert_eq!(vec!["a", "c", "sub"], sto.list(ObjectName::empty()).await.unwrap());
+
+            let metadata = sto.stat(c, true).await.unwrap();
+            assert_eq!(9, metadata.size());
+            assert_eq!(Some(&ContentDigest::of(b"different")), metadata.digest());
+            let broken = ObjectName::new("broken").unwrap();
+            sto.inner().write_bytes(ObjectName::from_path("refs/broken").unwrap(), b"no digest").await.unwrap();
+            assert!(sto.stat(broken, false).await.is_err());
+            sto.delete(broken).await.unwrap();
+
+            // snapshots stay the same when the original changes
+            let snap = ObjectName::new("snap").unwrap();
+            sto.snapshot(ObjectName::new("sub").unwrap(), snap).await.unwrap();
+            assert!(sto.snapshot(ObjectName::new("sub").unwrap(), snap).await.unwrap_err().is_already_exists());
+            sto.write_bytes(b, b"changed").await.unwrap();
+            assert_eq!(b"same".to_vec(), sto.read_bytes(ObjectName::from_path("snap/b").unwrap()).await.unwrap());
+
+            // blobs are kept as long as anything refers to them
+            sto.delete(a).await.unwrap();
+            assert_eq!(0, sto.gc().await.unwrap());
+            sto.delete(ObjectName::from_path("snap/b").unwrap()).await.unwrap();
+            assert_eq!(1, sto.gc().await.unwrap());
+            assert_eq!(2, sto.inner().walk(blobs, &all).await.unwrap().len());
+            assert_eq!(b"changed".to_vec(), sto.read_bytes(b).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_dedup_indexer() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize,Ordering};
+
+        let sto = ContentAddressedStorage::new(MemoryStorage::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counting_keymap = {
+            let calls = calls.clone();
+            move |sto: ContentAddressedStorage<MemoryStorage>, name: ObjectNameBuf| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                index_by_number(sto, name)
+            }
+        };
+
+        block_on(async {
+            for (path, number) in [("one", 1), ("sub/one", 1), ("two", 2)].iter() {
+                let obj = TestIndexData {
+                    number: *number
+                };
+                sto.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+
+            // the digest comes along with the metadata, so recording it
+            // doesn't read the reference twice
+            let one = ObjectName::new("one").unwrap();
+            assert!(sto.stat(one, false).await.unwrap().digest().is_some());
+
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let number_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &options, counting_keymap.clone())
+                .await.unwrap();
+            assert_eq!(3, calls.swap(0, Ordering::SeqCst));
+            assert_eq!(2, number_index.get(&1).unwrap().len());
+
+            // objects with the same contents share the key of the first one
+            let dedup = IndexOptions::new().dedup_content(true);
+            let number_index = HashTableIndexer::index_with_options(&sto, ObjectName::empty(), &options, &dedup, counting_keymap)
+                .await.unwrap();
+            assert_eq!(2, calls.load(Ordering::SeqCst));
+            let mut ones = number_index.get(&1).unwrap();
+            ones.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+            assert_eq!(vec![ObjectName::new("one").unwrap(), ObjectName::from_path("sub/one").unwrap()], ones);
+            assert_eq!(vec![ObjectName::new("two").unwrap()], number_index.get(&2).unwrap());
+        });
+    }
+
+    #[test]
+    fn test_update_index() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize,Ordering};
+
+        let sto = MemoryStorage::new();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counting_keymap = {
+            let calls = calls.clone();
+            move |sto: MemoryStorage, name: ObjectNameBuf| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                index_by_number(sto, name)
+            }
+        };
+
+        block_on(async {
+            for (filename, number) in [("one", 1), ("two", 2), ("three", 3)].iter() {
+                let obj = TestIndexData {
+                    number: *number
+                };
+                sto.write_json(ObjectName::new(filename).unwrap(), &obj).await.unwrap();
+            }
+
+            let options = WalkOptions::new().include_dirs(false);
+            let tracked = IndexOptions::new().track_changes(true);
+            let mut number_index = HashTableIndexer::index_with_options(&sto, ObjectName::empty(), &options, &tracked, counting_keymap.clone())
+                .await.unwrap();
+            assert_eq!(3, calls.swap(0, Ordering::SeqCst));
+
+            number_index.update(&sto, ObjectName::empty(), &options, &tracked, counting_keymap.clone()).await.unwrap();
+            assert_eq!(0, calls.load(Ordering::SeqCst));
+
+            sto.write_json(ObjectName::new("two").unwrap(), &TestIndexData { number: 22 }).await.unwrap();
+            sto.write_json(ObjectName::new("four").unwrap(), &TestIndexData { number: 4 }).await.unwrap();
+            sto.delete(ObjectName::new("three").unwrap()).await.unwrap();
+            number_index.update(&sto, ObjectName::empty(), &options, &tracked, counting_keymap.clone()).await.unwrap();
+            assert_eq!(2, calls.swap(0, Ordering::SeqCst));
+            assert_eq!(vec![ObjectName::new("one").unwrap()], number_index.get(&1).unwrap());
+            assert!(number_index.get(&2).unwrap().is_empty());
+            assert_eq!(vec![ObjectName::new("two").unwrap()], number_index.get(&22).unwrap());
+            assert!(number_index.get(&3).unwrap().is_empty());
+            assert_eq!(vec![ObjectName::new("four").unwrap()], number_index.get(&4).unwrap());
+
+            // without tracking, the first update runs the keymap on everything
+            let untracked = WalkOptions::new().include_dirs(false);
+            let mut number_index = HashTableIndexer::index_with(&sto, ObjectName::empty(), &untracked, counting_keymap.clone())
+                .await.unwrap();
+            number_index.update(&sto, ObjectName::empty(), &untracked, &IndexOptions::new(), counting_keymap.clone()).await.unwrap();
+            assert_eq!(6, calls.swap(0, Ordering::SeqCst));
+            number_index.update(&sto, ObjectName::empty(), &untracked, &IndexOptions::new(), counting_keymap).await.unwrap();
+            assert_eq!(0, calls.load(Ordering::SeqCst));
+            assert_eq!(3, number_index.keys().count());
+        });
+
+        // a modification time not older than the build can't be trusted
+        let dir = TempDir::default();
+        let sto = FileStorage::new(dir.as_ref());
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+        let write = |number| {
+            let path = dir.as_ref().join("racy");
+            std::fs::write(&path, serde_json::to_vec(&TestIndexData { number }).unwrap()).unwrap();
+            std::fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+        };
+
+        block_on(async {
+            write(1);
+            let options = WalkOptions::new().include_dirs(false);
+            let tracked = IndexOptions::new().track_changes(true);
+            let mut number_index = HashTableIndexer::index_with_options(&sto, ObjectName::empty(), &options, &tracked, index_by_number)
+                .await.unwrap();
+            write(2);
+            number_index.update(&sto, ObjectName::empty(), &options, &tracked, index_by_number).await.unwrap();
+            assert_eq!(vec![ObjectName::new("racy").unwrap()], number_index.get(&2).unwrap());
+            assert!(number_index.get(&1).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_save_index() {
+        let sto = MemoryStorage::new();
+        let saved = MemoryStorage::new();
+        let name = ObjectName::new("numbers.idx").unwrap();
+
+        block_on(async {
+            for (i, filename) in ["foo", "bar", "baz"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::new(filename).unwrap(), &obj).await.unwrap();
+            }
+
+            let options = WalkOptions::new().include_dirs(false);
+            let tracked = IndexOptions::new().track_changes(true);
+            let number_index = HashTableIndexer::index_with_options(&sto, ObjectName::empty(), &options, &tracked, index_by_number)
+                .await.unwrap()
+                .keymap_id("by-number")
+                .storage_id("memory:numbers");
+            number_index.save(&saved, name).await.unwrap();
+
+            let header = HashTableIndexer::<i32>::read_header(&saved, name).await.unwrap();
+            assert_eq!(number_index.header(), header);
+            assert_eq!(1, header.version());
+            assert_eq!(3, header.objects());
+            assert_eq!(Some("by-number"), header.keymap_id());
+            assert_eq!(Some("memory:numbers"), header.storage_id());
+            assert!(header.built().is_some());
+
+            let mut loaded = HashTableIndexer::<i32>::load(&saved, name).await.unwrap();
+            assert_eq!(header, loaded.header());
+            assert_eq!(vec![ObjectName::new("bar").unwrap()], loaded.get(&1).unwrap());
+            loaded.update(&sto, ObjectName::empty(), &options, &tracked, index_by_number).await.unwrap();
+            assert_eq!(3, loaded.keys().count());
+
+            saved.write_bytes(name, b"{\"version\":2}\n{}").await.unwrap();
+            assert!(matches!(HashTableIndexer::<i32>::read_header(&saved, name).await, Err(IdxError::UnsupportedVersion(2))));
+            assert!(matches!(HashTableIndexer::<i32>::load(&saved, name).await, Err(IdxError::UnsupportedVersion(2))));
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "watch")]
+    fn test_watch() {
+        use crate::WatchEvent;
+        use futures::StreamExt;
+        use std::time::Duration;
+
+        async fn next_event(watch: &mut crate::Watch) -> WatchEvent {
+            tokio::time::timeout(Duration::from_secs(5), watch.next()).await
+                .expect("no event in time").unwrap()
+        }
+
+        let dir = TempDir::default();
+        let sto = FileStorage::new(dir.as_ref());
+        let mut watch = sto.watch(Duration::from_millis(50)).unwrap();
+        let name = |path| ObjectNameBuf::from_path(path).unwrap();
+
+        block_on(async {
+
+            // the temporary files of atomic writes stay hidden
+            sto.write_bytes(ObjectName::new("a").unwrap(), b"a").await.unwrap();
+            assert_eq!(WatchEvent::Created(name("a")), next_event(&mut watch).await);
+            sto.write_bytes(ObjectName::new("a").unwrap(), b"aa").await.unwrap();
+            assert_eq!(WatchEvent::Created(name("a")), next_event(&mut watch).await);
+
+            sto.rename(ObjectName::new("a").unwrap(), ObjectName::new("b").unwrap()).await.unwrap();
+            assert_eq!(WatchEvent::Renamed(name("a"), name("b")), next_event(&mut watch).await);
+
+            sto.create_dir(ObjectName::new("sub").unwrap()).await.unwrap();
+            assert_eq!(WatchEvent::Created(name("sub")), next_event(&mut watch).await);
+            std::fs::write(dir.as_ref().join("sub/c"), b"c").unwrap();
+            assert_eq!(WatchEvent::Created(name("sub/c")), next_event(&mut watch).await);
+            std::fs::write(dir.as_ref().join("sub/c"), b"cc").unwrap();
+            assert_eq!(WatchEvent::Modified(name("sub/c")), next_event(&mut watch).await);
+
+            sto.delete(ObjectName::new("b").unwrap()).await.unwrap();
+            assert_eq!(WatchEvent::Deleted(name("b")), next_event(&mut watch).await);
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "watch")]
+    fn test_live_index() {
+        use crate::{LiveIndex,WatchEvent};
+
+        let dir = TempDir::default();
+        let sto = FileStorage::new(dir.as_ref());
+        let name = |path| ObjectNameBuf::from_path(path).unwrap();
+
+        block_on(async {
+            for (path, number) in [("one", 1), ("sub/two", 2)].iter() {
+                let obj = TestIndexData {
+                    number: *number
+                };
+                sto.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let live = LiveIndex::index_with(&sto, ObjectName::empty(), &options, index_by_number).await.unwrap();
+            let before = live.snapshot();
+
+            sto.write_json(ObjectName::new("three").unwrap(), &TestIndexData { number: 3 }).await.unwrap();
+            live.apply(WatchEvent::Created(name("three"))).await.unwrap();
+            assert_eq!(vec![ObjectName::new("three").unwrap()], live.snapshot().get(&3).unwrap());
+            // snapshots taken earlier stay unchanged
+            assert!(before.get(&3).unwrap().is_empty());
+
+            sto.write_json(ObjectName::new("one").unwrap(), &TestIndexData { number: 4 }).await.unwrap();
+            live.apply(WatchEvent::Modified(name("one"))).await.unwrap();
+            assert!(live.snapshot().get(&1).unwrap().is_empty());
+            assert_eq!(vec![ObjectName::new("one").unwrap()], live.snapshot().get(&4).unwrap());
+
+            // renaming a directory moves everything below it
+            sto.rename(ObjectName::new("sub").unwrap(), ObjectName::new("moved").unwrap()).await.unwrap();
+            live.apply(WatchEvent::Renamed(name("sub"), name("moved"))).await.unwrap();
+            assert_eq!(vec![ObjectName::from_path("moved/two").unwrap()], live.snapshot().get(&2).unwrap());
+
+            // renaming over an indexed object replaces its keys
+            sto.write_json(ObjectName::new("six").unwrap(), &TestIndexData { number: 6 }).await.unwrap();
+            live.apply(WatchEvent::Created(name("six"))).await.unwrap();
+            std::fs::rename(dir.as_ref().join("six"), dir.as_ref().join("three")).unwrap();
+            live.apply(WatchEvent::Renamed(name("six"), name("three"))).await.unwrap();
+            assert!(live.snapshot().get(&3).unwrap().is_empty());
+            assert_eq!(vec![ObjectName::new("three").unwrap()], live.snapshot().get(&6).unwrap());
+
+            sto.delete(ObjectName::new("three").unwrap()).await.unwrap();
+            live.apply(WatchEvent::Deleted(name("three"))).await.unwrap();
+            assert!(live.snapshot().get(&6).unwrap().is_empty());
+
+            // objects the keymap fails on are left out
+            sto.write_bytes(ObjectName::new("broken").unwrap(), b"{").await.unwrap();
+            live.apply(WatchEvent::Created(name("broken"))).await.unwrap();
+            assert_eq!(2, live.snapshot().keys().count());
+
+            let (tx, rx) = futures::channel::mpsc::unbounded();
+            let task = live.subscribe(rx);
+            sto.write_json(ObjectName::new("five").unwrap(), &TestIndexData { number: 5 }).await.unwrap();
+            tx.unbounded_send(WatchEvent::Created(name("five"))).unwrap();
+            drop(tx);
+            task.await.unwrap();
+            assert_eq!(vec![ObjectName::new("five").unwrap()], live.snapshot().get(&5).unwrap());
+
+            live.apply(WatchEvent::Rescan).await.unwrap();
+            let mut keys: Vec<_> = live.snapshot().keys().cloned().collect();
+            keys.sort();
+            assert_eq!(vec![2, 4, 5], keys);
+        });
+    }
+
+    #[test]
+    fn test_cached_indexer() {
+        let sto = CachedStorage::new(MemoryStorage::new(), 1 << 20);
+
+        block_on(async {
+            for (i, filename) in ["foo", "bar", "baz"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::new(filename).unwrap(), &obj).await.unwrap();
+            }
+
+            let number_index = HashTableIndexer::index(&sto, ObjectName::empty(), index_by_number)
+                .await.unwrap();
+            let name_index = HashTableIndexer::index(&sto, ObjectName::empty(), index_by_name)
+                .await.unwrap();
+
+            assert_eq!(vec![ObjectName::new("bar").unwrap()], number_index.get(&1).unwrap());
+            assert_eq!(vec![ObjectName::new("baz").unwrap()], name_index.get(&"baz".to_string()).unwrap());
+            assert!(sto.cached_bytes() > 0);
+        });
+    }
+
+    #[test]
+    fn test_recursive_indexer() {
+        let dir = TempDir::default();
+        std::fs::create_dir_all(dir.as_ref().join("sub/subsub")).unwrap();
+        let sto = FileStorage::new(dir.as_ref());
+
+        block_on(async {
+            for (i, path) in ["one", "sub/two", "sub/subsub/three"].iter().enumerate() {
+                let obj = TestIndexData {
+                    number: i as i32
+                };
+                sto.write_json(ObjectName::from_path(path).unwrap(), &obj).await.unwrap();
+            }
+
+            // directories are skipped by default
+            let top_index = HashTableIndexer::index(&sto, ObjectName::empty(), index_by_number)
+                .await.unwrap();
+            assert_eq!(1, top_index.keys().count());
+
+            // unless asked for, when they make the keymap fail
+            let with_dirs = WalkOptions::new();
+            assert!(HashTableIndexer::index_with(&sto, ObjectName::empty(), &with_dirs, index_by_number)
+                .await.is_err());
+
+            let options = WalkOptions::new().recursive().include_dirs(false);
+            let number_index = HashTableIndexer::index_with(&sto,
+                                                            ObjectName::empty(),
+                                                            &options,
+                                                            index_by_number)
+                .await.unwrap();
+
+            assert_eq!(vec![ObjectName::from_path("sub/subsub/three").unwrap()],
+                       number_index.get(&2).unwrap());
+            assert_eq!(3, number_index.keys().count());
+        });
+    }
+
     fn score_number(query: &i32, key: &i32) -> f64 {
         1.0 - ((*key as f64) - (*query as f64)).abs()
     }
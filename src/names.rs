@@ -1,6 +1,7 @@
 use crate::{IdxResult,IdxError};
 
 use serde::{Serialize,Deserialize};
+use std::convert::TryFrom;
 
 #[derive(Clone,Copy,Debug,PartialEq)]
 pub struct ObjectName<'a> {
@@ -9,7 +10,9 @@ pub struct ObjectName<'a> {
 
 impl<'a> ObjectName<'a> {
     pub fn new(name: &'a str) -> IdxResult<Self> {
-        if Self::is_valid_object_name(name) {
+        if Self::is_dot_segment(name) {
+            Err(IdxError::DotSegment(name.to_string()))
+        } else if Self::is_valid_object_name(name) {
             Ok(Self {
                 name: name
             })
@@ -24,6 +27,26 @@ impl<'a> ObjectName<'a> {
         }
     }
 
+    /// Create a name referring to an object inside (nested) directories
+    ///
+    /// The path consists of valid object names separated by `/`.
+    pub fn from_path(path: &'a str) -> IdxResult<Self> {
+        if path.split('/').any(Self::is_dot_segment) {
+            Err(IdxError::DotSegment(path.to_string()))
+        } else if path.split('/').all(|seg| !seg.is_empty() && Self::is_valid_object_name(seg)) {
+            Ok(Self {
+                name: path
+            })
+        } else {
+            Err(IdxError::storage_error_msg(format!("The path given is not a valid object path: '{}'", path)))
+        }
+    }
+
+    /// Segments that would leave the current directory when used as a path
+    fn is_dot_segment(name: &str) -> bool {
+        name == "." || name == ".."
+    }
+
     fn is_valid_object_name(name: &str) -> bool {
         name.chars()
             .all(|c| {
@@ -41,14 +64,50 @@ impl<'a> ObjectName<'a> {
     pub fn as_str(&'a self) -> &'a str {
         self.name
     }
+
+    /// Decode a name produced by `ObjectNameBuf::escape` back to raw bytes
+    pub fn unescape(&self) -> IdxResult<Vec<u8>> {
+        let bytes = self.name.as_bytes();
+        let mut rv = Vec::with_capacity(bytes.len());
+
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] != b'%' {
+                rv.push(bytes[i]);
+                i += 1;
+                continue;
+            }
+
+            // an escaped separator would smuggle extra segments into the path
+            let byte = self.name.get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .filter(|b| *b != b'/')
+                .ok_or_else(|| IdxError::storage_error_msg(format!("Invalid escape sequence in object name: '{}'", self.name)))?;
+            rv.push(byte);
+            i += 3;
+        }
+
+        if rv.split(|b| *b == b'/').any(|seg| seg == b"." || seg == b"..") {
+            return Err(IdxError::DotSegment(self.name.to_string()));
+        }
+
+        Ok(rv)
+    }
 }
 
 
-#[derive(Clone,Debug,Serialize,Deserialize)]
+#[derive(Clone,Debug,PartialEq,Eq,Hash,Serialize,Deserialize)]
+#[serde(try_from = "UncheckedObjectNameBuf")]
 pub struct ObjectNameBuf {
     name: String
 }
 
+/// Deserialized form of an `ObjectNameBuf` before validating the name
+#[derive(Deserialize)]
+struct UncheckedObjectNameBuf {
+    name: String
+}
+
 impl ObjectNameBuf {
     pub fn new() -> Self {
         Self {
@@ -65,6 +124,43 @@ impl ObjectNameBuf {
         })
     }
 
+    pub fn from_path(path: impl AsRef<str>) -> IdxResult<Self> {
+        let ptr = ObjectName::from_path(path.as_ref())?;
+        let s = ptr.as_str().to_string();
+
+        Ok(Self {
+            name: s
+        })
+    }
+
+    /// Create a name from a raw byte path that need not be valid unicode
+    ///
+    /// `/` separates path segments. Bytes that aren't valid UTF-8 or that
+    /// can't appear in object names are replaced by `%XX` escapes, and `%` by
+    /// `%25`, so `ObjectName::unescape` restores the original bytes.
+    pub fn escape(raw: &[u8]) -> IdxResult<Self> {
+        let mut s = String::with_capacity(raw.len());
+
+        for chunk in raw.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                if c == '%' || c == '\\' || char::is_control(c) {
+                    let mut buf = [0u8; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        s.push_str(&format!("%{:02X}", b));
+                    }
+                } else {
+                    s.push(c);
+                }
+            }
+
+            for b in chunk.invalid() {
+                s.push_str(&format!("%{:02X}", b));
+            }
+        }
+
+        Self::from_path(s)
+    }
+
     pub fn name<'a>(&'a self) -> ObjectName<'a> {
         ObjectName {
             name: &self.name
@@ -72,6 +168,32 @@ impl ObjectNameBuf {
     }
 }
 
+impl Default for ObjectNameBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> From<ObjectName<'a>> for ObjectNameBuf {
+    fn from(name: ObjectName<'a>) -> Self {
+        Self {
+            name: name.as_str().to_string()
+        }
+    }
+}
+
+impl TryFrom<UncheckedObjectNameBuf> for ObjectNameBuf {
+    type Error = IdxError;
+
+    fn try_from(unchecked: UncheckedObjectNameBuf) -> IdxResult<Self> {
+        if unchecked.name.is_empty() {
+            Ok(Self::new())
+        } else {
+            Self::from_path(unchecked.name)
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod test {
@@ -88,4 +210,68 @@ mod test {
         assert!(ObjectName::new("\\").is_err());
         assert!(ObjectName::new("\n").is_err());
     }
+
+    #[test]
+    fn test_valid_paths() {
+        assert!(ObjectName::from_path("hello").is_ok());
+        assert!(ObjectName::from_path("hello/world").is_ok());
+        assert!(ObjectName::from_path("a/b/c.json").is_ok());
+
+        assert!(ObjectName::from_path("").is_err());
+        assert!(ObjectName::from_path("/").is_err());
+        assert!(ObjectName::from_path("/abs").is_err());
+        assert!(ObjectName::from_path("trailing/").is_err());
+        assert!(ObjectName::from_path("double//slash").is_err());
+        assert!(ObjectName::from_path("back\\slash").is_err());
+    }
+
+    #[test]
+    fn test_dot_segments() {
+        assert!(ObjectName::new(".hidden").is_ok());
+        assert!(ObjectName::new("...").is_ok());
+        assert!(ObjectName::from_path("a/..b").is_ok());
+
+        assert!(matches!(ObjectName::new("."), Err(IdxError::DotSegment(_))));
+        assert!(matches!(ObjectName::new(".."), Err(IdxError::DotSegment(_))));
+        assert!(matches!(ObjectName::from_path("a/../b"), Err(IdxError::DotSegment(_))));
+        assert!(matches!(ObjectName::from_path("../a"), Err(IdxError::DotSegment(_))));
+        assert!(matches!(ObjectName::from_path("a/."), Err(IdxError::DotSegment(_))));
+    }
+
+    #[test]
+    fn test_escape_round_trip() {
+        let raws: [&[u8]; 5] = [
+            b"plain",
+            b"dir/50%.json",
+            b"back\\slash\n",
+            b"latin1_\xe4\xf6\xfc",
+            "äöü/\u{85}".as_bytes(),
+        ];
+
+        for raw in raws.iter() {
+            let escaped = ObjectNameBuf::escape(raw).unwrap();
+            assert_eq!(raw.to_vec(), escaped.name().unescape().unwrap());
+        }
+
+        assert_eq!("dir/50%25.json", ObjectNameBuf::escape(b"dir/50%.json").unwrap().name().as_str());
+        assert_eq!("latin1_%E4", ObjectNameBuf::escape(b"latin1_\xe4").unwrap().name().as_str());
+        assert_eq!("äöü", ObjectNameBuf::escape("äöü".as_bytes()).unwrap().name().as_str());
+
+        assert!(ObjectName::new("50%.json").unwrap().unescape().is_err());
+        assert!(ObjectName::new("trailing%2").unwrap().unescape().is_err());
+        assert!(ObjectNameBuf::escape(b"..").is_err());
+
+        // escapes must not introduce separators or dot segments
+        assert!(ObjectName::new("a%2F..%2Fb").unwrap().unescape().is_err());
+        assert!(matches!(ObjectName::from_path("a/%2E%2E/b").unwrap().unescape(), Err(IdxError::DotSegment(_))));
+    }
+
+    #[test]
+    fn test_deserialize_validates() {
+        let ok: ObjectNameBuf = serde_json::from_str(r#"{"name":"a/b"}"#).unwrap();
+        assert_eq!("a/b", ok.name().as_str());
+
+        assert!(serde_json::from_str::<ObjectNameBuf>(r#"{"name":".."}"#).is_err());
+        assert!(serde_json::from_str::<ObjectNameBuf>(r#"{"name":"a/../../etc"}"#).is_err());
+    }
 }
\ No newline at end of file
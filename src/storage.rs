@@ -1,9 +1,46 @@
 pub(crate) mod fs;
+pub(crate) mod memory;
+pub(crate) mod cached;
+pub(crate) mod compressed;
+pub(crate) mod overlay;
+pub(crate) mod content_addressed;
+#[cfg(feature = "encryption")]
+pub(crate) mod encrypted;
+#[cfg(feature = "archive")]
+pub(crate) mod archive;
+#[cfg(feature = "git")]
+pub(crate) mod git;
+#[cfg(feature = "sqlite")]
+pub(crate) mod sqlite;
+#[cfg(feature = "s3")]
+pub(crate) mod s3;
+#[cfg(feature = "http")]
+pub(crate) mod http;
+mod transform;
+pub(crate) mod walk;
+pub(crate) mod entry;
+pub(crate) mod metadata;
 
-use crate::{IdxResult,ObjectName};
+use crate::{IdxError,IdxResult,ObjectName,ObjectNameBuf};
+use crate::codec::{Codec,Json};
+use crate::format::decode_auto;
+use walk::{WalkOptions,SymlinkPolicy};
+use entry::{DirEntry,EntryKind};
+use metadata::{ContentDigest,ObjectMetadata};
 
 use async_trait::async_trait;
 use serde::{Serialize,de::DeserializeOwned};
+use std::io;
+use tokio::io::{AsyncRead,AsyncWrite};
+
+
+/// Stream for reading the contents of an object
+pub type ObjectReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Stream for writing the contents of an object
+///
+/// The object is only complete after `shutdown` was called on the writer.
+pub type ObjectWriter = Box<dyn AsyncWrite + Send + Unpin>;
 
 
 #[async_trait]
@@ -13,32 +50,198 @@ pub trait AccessStorage {
     /// List directory contents
     async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter>;
 
+    /// List directory contents together with the kind of each entry
+    ///
+    /// The default implementation fails with a storage error of kind
+    /// `Unsupported`, which also makes the default `walk` fail.
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        Err(unsupported("listing entries", dir_name))
+    }
+
+    /// List objects below a directory, descending as far as the options allow
+    ///
+    /// The default implementation is built on `list_entries`. It has no way to
+    /// resolve symlinks, so these are never descended into.
+    async fn walk(&self, start: ObjectName<'_>, options: &WalkOptions) -> IdxResult<Vec<ObjectNameBuf>> {
+        let mut rv = vec![];
+        if !options.descends_below(0) {
+            return Ok(rv);
+        }
+
+        let mut pending = vec![(ObjectNameBuf::from(start), 0)];
+        while let Some((dir_name, depth)) = pending.pop() {
+            for entry in self.list_entries(dir_name.name()).await? {
+                match entry.kind() {
+                    EntryKind::Symlink if options.symlink_policy() == SymlinkPolicy::Skip => (),
+                    EntryKind::Dir => {
+                        if options.includes_dirs() {
+                            rv.push(entry.clone().into_name());
+                        }
+                        if options.descends_below(depth + 1) {
+                            pending.push((entry.into_name(), depth + 1));
+                        }
+                    }
+                    _ => rv.push(entry.into_name()),
+                }
+            }
+        }
+
+        Ok(rv)
+    }
+
     /// Read raw bytes from an object
     async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>>;
 
+    /// Open an object for reading it as a stream
+    ///
+    /// The default implementation reads the whole object up front.
+    async fn open_read(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectReader> {
+        let byte_data = self.read_bytes(obj_name).await?;
+
+        Ok(Box::new(io::Cursor::new(byte_data)))
+    }
+
+    /// Open an object for writing it as a stream, replacing existing contents
+    ///
+    /// The default implementation fails with a storage error of kind
+    /// `Unsupported`.
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        Err(unsupported("streaming writes", obj_name))
+    }
+
+    /// Query size, modification time and optionally the content digest of an object
+    ///
+    /// Storages that know the digest without reading the object may include
+    /// it even if `with_digest` is not set. The default implementation reads
+    /// the whole object and cannot tell the modification time.
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let byte_data = self.read_bytes(obj_name).await?;
+        let digest = if with_digest {
+            Some(ContentDigest::of(&byte_data))
+        } else {
+            None
+        };
+
+        Ok(ObjectMetadata::new(byte_data.len() as u64, None, digest))
+    }
+
+    /// Digest of an object if the storage knows it without reading the object
+    ///
+    /// The default implementation returns `None`.
+    async fn content_digest(&self, _obj_name: ObjectName<'_>) -> IdxResult<Option<ContentDigest>> {
+        Ok(None)
+    }
+
     /// Write an object by providing raw bytes
     async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
         where
             T: AsRef<[u8]> + Unpin + Send;
 
-    /// Read a JSON object and directly deserialize it before returning
-    async fn read_json<T>(&self, obj_name: ObjectName<'_>) -> IdxResult<Box<T>>
+    /// Check whether an object or directory exists
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        match self.stat(obj_name, false).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.is_not_found() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete an object or an empty directory
+    ///
+    /// Fails with a not-found storage error if there is nothing to delete.
+    /// The default implementation fails with a storage error of kind
+    /// `Unsupported`.
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(unsupported("deleting", obj_name))
+    }
+
+    /// Move an object to a new name, never replacing an existing object
+    ///
+    /// Fails with a not-found storage error if `from` is missing and with an
+    /// already-exists storage error if `to` is taken. The default
+    /// implementation fails with a storage error of kind `Unsupported`.
+    async fn rename(&self, from: ObjectName<'_>, _to: ObjectName<'_>) -> IdxResult<()> {
+        Err(unsupported("renaming", from))
+    }
+
+    /// Copy an object to a new name, never replacing an existing object
+    ///
+    /// The default implementation reads and writes the whole object.
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let byte_data = self.read_bytes(from).await?;
+        if self.exists(to).await? {
+            let msg = format!("Object exists: '{}'", to.as_str());
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+
+        self.write_bytes(to, byte_data).await
+    }
+
+    /// Create an empty directory
+    ///
+    /// Fails with an already-exists storage error if the name is taken.
+    /// The default implementation fails with a storage error of kind
+    /// `Unsupported`.
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(unsupported("creating directories", dir_name))
+    }
+
+    /// Read an object and deserialize it with the given codec
+    async fn read_as<C,T>(&self, obj_name: ObjectName<'_>) -> IdxResult<T>
         where
+            C: Codec,
             T: DeserializeOwned
     {
         let byte_data = self.read_bytes(obj_name).await?;
 
-        Ok(serde_json::from_slice(&byte_data)?)
+        C::decode(&byte_data)
     }
 
-    /// Write an object as JSON file
-    async fn write_json<T>(&self, obj_name: ObjectName<'_>, obj: T) -> IdxResult<()>
+    /// Serialize an object with the given codec and write it
+    async fn write_as<C,T>(&self, obj_name: ObjectName<'_>, obj: T) -> IdxResult<()>
         where
+            C: Codec,
             T: Serialize + Send
     {
-        let byte_data = serde_json::to_vec(&obj)?;
+        let byte_data = C::encode(&obj)?;
 
         self.write_bytes(obj_name, byte_data).await
     }
+
+    /// Read an object and deserialize it after detecting compression and format
+    ///
+    /// See `detect_format` for the rules used. Compressed objects may expand
+    /// to at most `DEFAULT_MAX_DECOMPRESSED_SIZE`, use `decode_auto_limited`
+    /// on the result of `read_bytes` for another limit.
+    async fn read_auto<T>(&self, obj_name: ObjectName<'_>) -> IdxResult<T>
+        where
+            T: DeserializeOwned
+    {
+        let byte_data = self.read_bytes(obj_name).await?;
+
+        decode_auto(obj_name, &byte_data)
+    }
+
+    /// Read a JSON object and directly deserialize it before returning
+    async fn read_json<T>(&self, obj_name: ObjectName<'_>) -> IdxResult<Box<T>>
+        where
+            T: DeserializeOwned
+    {
+        Ok(Box::new(self.read_as::<Json,T>(obj_name).await?))
+    }
+
+    /// Write an object as JSON file
+    async fn write_json<T>(&self, obj_name: ObjectName<'_>, obj: T) -> IdxResult<()>
+        where
+            T: Serialize + Send
+    {
+        self.write_as::<Json,T>(obj_name, obj).await
+    }
 }
 
+
+/// Storage error for an operation a storage doesn't implement
+fn unsupported(operation: &str, name: ObjectName<'_>) -> IdxError {
+    let msg = format!("Storage doesn't support {}: '{}'", operation, name.as_str());
+    io::Error::new(io::ErrorKind::Unsupported, msg).into()
+}
@@ -0,0 +1,402 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::collections::{BTreeMap,BTreeSet};
+use std::fs::File;
+use std::io::{self,Read};
+use std::path::PathBuf;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use async_trait::async_trait;
+use tokio::task::spawn_blocking;
+
+/// Files and directories of an archive, keyed by their object names
+///
+/// Parent directories of all members are present even if the archive has no
+/// entries for them.
+struct ArchiveTree<T> {
+    files: BTreeMap<String,T>,
+    dirs: BTreeSet<String>,
+}
+
+impl<T> ArchiveTree<T> {
+    fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            dirs: BTreeSet::new(),
+        }
+    }
+
+    fn insert_file(&mut self, name: ObjectNameBuf, member: T) {
+        self.insert_parents(name.name());
+        self.files.insert(name.name().as_str().to_string(), member);
+    }
+
+    fn insert_dir(&mut self, name: ObjectNameBuf) {
+        self.insert_parents(name.name());
+        self.dirs.insert(name.name().as_str().to_string());
+    }
+
+    fn insert_parents(&mut self, name: ObjectName<'_>) {
+        let name = name.as_str();
+
+        for (pos, _) in name.match_indices('/') {
+            self.dirs.insert(name[..pos].to_string());
+        }
+    }
+
+    fn get(&self, name: ObjectName<'_>) -> IdxResult<&T> {
+        self.files.get(name.as_str())
+            .ok_or_else(|| not_found(name))
+    }
+
+    fn exists(&self, name: ObjectName<'_>) -> bool {
+        name.as_str().is_empty()
+            || self.files.contains_key(name.as_str())
+            || self.dirs.contains(name.as_str())
+    }
+
+    fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let dir = dir_name.as_str();
+        if !dir.is_empty() && !self.dirs.contains(dir) {
+            if self.files.contains_key(dir) {
+                return Err(not_a_directory(dir_name));
+            }
+            return Err(not_found(dir_name));
+        }
+
+        let prefix = if dir.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir)
+        };
+        let is_child = |name: &&String| {
+            name.starts_with(&prefix) && !name[prefix.len()..].contains('/')
+        };
+
+        let dirs = self.dirs.iter()
+            .filter(is_child)
+            .map(|name| (name, EntryKind::Dir));
+        let files = self.files.keys()
+            .filter(is_child)
+            .map(|name| (name, EntryKind::File));
+
+        dirs.chain(files)
+            .map(|(name, kind)| Ok(DirEntry::new(ObjectNameBuf::from_path(name)?, kind)))
+            .collect()
+    }
+}
+
+
+/// Turn the path of an archive member into an object name
+///
+/// Members with absolute paths, dot segments or names that aren't valid object
+/// names are left out.
+fn member_name(path: &str) -> Option<ObjectNameBuf> {
+    let mut path = path.trim_end_matches('/');
+    while let Some(rest) = path.strip_prefix("./") {
+        path = rest;
+    }
+
+    ObjectNameBuf::from_path(path).ok()
+}
+
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+fn not_a_directory(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotADirectory, format!("Not a directory: '{}'", name.as_str())).into()
+}
+
+fn read_only(name: ObjectName<'_>) -> IdxError {
+    IdxError::ReadOnly(name.as_str().to_string())
+}
+
+
+/// Metadata without a digest, or with the digest of the given contents
+fn metadata_of(data: &[u8], modified: Option<SystemTime>, with_digest: bool) -> ObjectMetadata {
+    let digest = if with_digest {
+        Some(ContentDigest::of(data))
+    } else {
+        None
+    };
+
+    ObjectMetadata::new(data.len() as u64, modified, digest)
+}
+
+
+/// A regular file in a tar archive
+struct TarMember {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+
+/// Read-only storage for the files in a tar archive
+///
+/// Gzip compressed archives are recognized by their contents. The contents
+/// of all files are read into memory when the archive is opened, since tar
+/// has no index to find members by, so the unpacked archive has to fit into
+/// memory. Only regular files and directories are available, links and
+/// special files are left out.
+#[derive(Clone)]
+pub struct TarStorage {
+    tree: Arc<ArchiveTree<TarMember>>,
+}
+
+
+impl TarStorage {
+    /// Read a tar or tar.gz archive from the filesystem
+    pub async fn open(path: impl Into<PathBuf>) -> IdxResult<Self> {
+        let path = path.into();
+
+        let storage = spawn_blocking(move || Self::from_reader(File::open(path)?))
+            .await
+            .map_err(IdxError::storage_error)??;
+        Ok(storage)
+    }
+
+    /// Read a tar or tar.gz archive held in memory
+    pub fn from_bytes(data: impl AsRef<[u8]>) -> IdxResult<Self> {
+        Ok(Self::from_reader(data.as_ref())?)
+    }
+
+    fn from_reader(reader: impl Read) -> io::Result<Self> {
+        let mut reader = io::BufReader::new(reader);
+        let is_gzip = io::BufRead::fill_buf(&mut reader)?.starts_with(&[0x1f, 0x8b]);
+
+        if is_gzip {
+            Self::read_archive(flate2::read::GzDecoder::new(reader))
+        } else {
+            Self::read_archive(reader)
+        }
+    }
+
+    fn read_archive(reader: impl Read) -> io::Result<Self> {
+        let mut tree = ArchiveTree::new();
+        let mut archive = tar::Archive::new(reader);
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let name = match member_name(&path) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_dir() {
+                tree.insert_dir(name);
+            } else if entry_type.is_file() {
+                let modified = UNIX_EPOCH + Duration::from_secs(entry.header().mtime()?);
+                // the size in the header can't be trusted for allocating
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data)?;
+                tree.insert_file(name, TarMember { data, modified });
+            }
+        }
+
+        Ok(Self {
+            tree: Arc::new(tree),
+        })
+    }
+}
+
+
+#[async_trait]
+impl AccessStorage for TarStorage {
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.tree.list_entries(dir_name)?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        self.tree.list_entries(dir_name)
+    }
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        Ok(self.tree.get(obj_name)?.data.clone())
+    }
+
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let member = self.tree.get(obj_name)?;
+
+        Ok(metadata_of(&member.data, Some(member.modified), with_digest))
+    }
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        Ok(self.tree.exists(obj_name))
+    }
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        Err(read_only(obj_name))
+    }
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, _: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        Err(read_only(name))
+    }
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(obj_name))
+    }
+
+    async fn rename(&self, from: ObjectName<'_>, _: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(from))
+    }
+
+    async fn copy(&self, _: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(to))
+    }
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(dir_name))
+    }
+}
+
+
+/// A regular file in a zip archive
+struct ZipMember {
+    index: usize,
+    size: u64,
+}
+
+
+/// Read-only storage for the files in a zip archive
+///
+/// The central directory is read when the archive is opened, members are
+/// decompressed on every read. Zip archives don't record time zones, so no
+/// modification times are reported.
+#[derive(Clone)]
+pub struct ZipStorage {
+    archive: Arc<Mutex<zip::ZipArchive<File>>>,
+    tree: Arc<ArchiveTree<ZipMember>>,
+}
+
+
+impl ZipStorage {
+    /// Open a zip archive from the filesystem
+    pub async fn open(path: impl Into<PathBuf>) -> IdxResult<Self> {
+        let path = path.into();
+
+        let storage = spawn_blocking(move || Self::read_archive(File::open(path)?))
+            .await
+            .map_err(IdxError::storage_error)??;
+        Ok(storage)
+    }
+
+    fn read_archive(file: File) -> io::Result<Self> {
+        let mut archive = zip::ZipArchive::new(file)
+            .map_err(io::Error::other)?;
+        let mut tree = ArchiveTree::new();
+
+        for i in 0..archive.len() {
+            let member = archive.by_index(i)
+                .map_err(io::Error::other)?;
+            let name = match member_name(member.name()) {
+                Some(name) => name,
+                None => continue,
+            };
+
+            if member.is_dir() {
+                tree.insert_dir(name);
+            } else if member.is_file() {
+                tree.insert_file(name, ZipMember { index: i, size: member.size() });
+            }
+        }
+
+        Ok(Self {
+            archive: Arc::new(Mutex::new(archive)),
+            tree: Arc::new(tree),
+        })
+    }
+}
+
+
+#[async_trait]
+impl AccessStorage for ZipStorage {
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.tree.list_entries(dir_name)?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        self.tree.list_entries(dir_name)
+    }
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let index = self.tree.get(obj_name)?.index;
+        let archive = self.archive.clone();
+
+        let data = spawn_blocking(move || -> io::Result<Vec<u8>> {
+            let mut archive = archive.lock()
+                .map_err(|_| io::Error::other("Zip archive lock poisoned"))?;
+            let mut member = archive.by_index(index)
+                .map_err(io::Error::other)?;
+
+            let mut data = Vec::new();
+            member.read_to_end(&mut data)?;
+            Ok(data)
+        })
+            .await
+            .map_err(IdxError::storage_error)??;
+        Ok(data)
+    }
+
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        if with_digest {
+            let data = self.read_bytes(obj_name).await?;
+            return Ok(metadata_of(&data, None, true));
+        }
+
+        let size = self.tree.get(obj_name)?.size;
+
+        Ok(ObjectMetadata::new(size, None, None))
+    }
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        Ok(self.tree.exists(obj_name))
+    }
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        Err(read_only(obj_name))
+    }
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, _: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        Err(read_only(name))
+    }
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(obj_name))
+    }
+
+    async fn rename(&self, from: ObjectName<'_>, _: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(from))
+    }
+
+    async fn copy(&self, _: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(to))
+    }
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(read_only(dir_name))
+    }
+}
@@ -0,0 +1,400 @@
+use super::{AccessStorage,ObjectName,ObjectReader,ObjectWriter,WalkOptions,DirEntry,ObjectMetadata,ContentDigest};
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::collections::{BTreeMap,HashMap};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc,Mutex,MutexGuard};
+use std::task::{Context,Poll};
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+
+/// Storage wrapper keeping recently read objects, listings and metadata in
+/// memory
+///
+/// The cache holds at most `capacity` bytes of object data, entry names and
+/// metadata and evicts the least recently used items first. Clones share the cache, so
+/// handing a `CachedStorage` to several indexers over the same objects reads
+/// every object only once.
+///
+/// Changes made through the wrapper invalidate the affected objects and
+/// listings. Changes made to the wrapped storage directly are not noticed.
+/// Walks and `exists` are not cached and always ask the wrapped storage.
+#[derive(Clone)]
+pub struct CachedStorage<S> {
+    inner: S,
+    cache: Arc<Mutex<LruCache>>,
+}
+
+#[derive(Clone,PartialEq,Eq,Hash)]
+enum CacheKey {
+    Object(String),
+    List(String),
+    Entries(String),
+    /// Metadata with or without the digest
+    Stat(String, bool),
+    Digest(String),
+}
+
+impl CacheKey {
+    fn name(&self) -> &str {
+        match self {
+            Self::Object(name) | Self::List(name) | Self::Entries(name) | Self::Stat(name, _) | Self::Digest(name) => name,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CacheValue {
+    Bytes(Vec<u8>),
+    List(Vec<String>),
+    Entries(Vec<DirEntry>),
+    Metadata(ObjectMetadata),
+    Digest(Option<ContentDigest>),
+}
+
+impl CacheValue {
+    fn size(&self) -> usize {
+        match self {
+            Self::Bytes(data) => data.len(),
+            Self::List(names) => names.iter().map(|n| n.len()).sum(),
+            Self::Entries(entries) => entries.iter().map(|e| e.name().as_str().len()).sum(),
+            Self::Metadata(_) => std::mem::size_of::<ObjectMetadata>(),
+            Self::Digest(_) => std::mem::size_of::<Option<ContentDigest>>(),
+        }
+    }
+}
+
+
+/// Byte-bounded cache ordered by the time of the last access
+struct LruCache {
+    capacity: usize,
+    used: usize,
+    tick: u64,
+    /// Incremented on every invalidation to drop results of reads that raced
+    /// with a write
+    generation: u64,
+    items: HashMap<CacheKey,(u64,CacheValue)>,
+    order: BTreeMap<u64,CacheKey>,
+}
+
+impl LruCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            tick: 0,
+            generation: 0,
+            items: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<CacheValue> {
+        let tick = self.next_tick();
+        let (last_used, value) = self.items.get_mut(key)?;
+
+        self.order.remove(last_used);
+        self.order.insert(tick, key.clone());
+        *last_used = tick;
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, value: CacheValue, generation: u64) {
+        let size = value.size();
+        if generation != self.generation || size > self.capacity {
+            return;
+        }
+
+        self.remove(&key);
+        while self.used + size > self.capacity {
+            let oldest = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(evicted) = self.order.remove(&oldest) {
+                self.remove(&evicted);
+            }
+        }
+
+        let tick = self.next_tick();
+        self.used += size;
+        self.order.insert(tick, key.clone());
+        self.items.insert(key, (tick, value));
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some((last_used, value)) = self.items.remove(key) {
+            self.order.remove(&last_used);
+            self.used -= value.size();
+        }
+    }
+
+    /// Forget everything that a change to `name` could make stale
+    ///
+    /// That is the object itself, anything below it in case it is a
+    /// directory, and the listings and metadata of all its ancestors.
+    fn invalidate(&mut self, name: &str) {
+        self.generation += 1;
+
+        let prefix = format!("{}/", name);
+        let stale: Vec<_> = self.items.keys()
+            .filter(|key| {
+                let key_name = key.name();
+                match key {
+                    CacheKey::Object(_) | CacheKey::Digest(_) => key_name == name || key_name.starts_with(&prefix),
+                    _ => key_name == name
+                        || key_name.starts_with(&prefix)
+                        || key_name.is_empty()
+                        || name.starts_with(&format!("{}/", key_name)),
+                }
+            })
+            .cloned()
+            .collect();
+
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.used = 0;
+        self.items.clear();
+        self.order.clear();
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+
+impl<S> CachedStorage<S> {
+    /// Wrap a storage with a cache holding at most `capacity` bytes
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Access the wrapped storage, bypassing the cache
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Number of bytes currently held by the cache
+    pub fn cached_bytes(&self) -> usize {
+        self.lock().map(|cache| cache.used).unwrap_or(0)
+    }
+
+    /// Drop all cached objects, listings and metadata
+    pub fn clear(&self) -> IdxResult<()> {
+        self.lock()?.clear();
+        Ok(())
+    }
+
+    fn lock(&self) -> IdxResult<MutexGuard<'_, LruCache>> {
+        lock_cache(&self.cache)
+            .map_err(IdxError::from)
+    }
+
+    fn lookup(&self, key: &CacheKey) -> IdxResult<(Option<CacheValue>, u64)> {
+        let mut cache = self.lock()?;
+        let generation = cache.generation;
+
+        Ok((cache.get(key), generation))
+    }
+
+    fn store(&self, key: CacheKey, value: CacheValue, generation: u64) -> IdxResult<()> {
+        self.lock()?.insert(key, value, generation);
+        Ok(())
+    }
+
+    fn invalidate(&self, name: ObjectName<'_>) -> IdxResult<()> {
+        self.lock()?.invalidate(name.as_str());
+        Ok(())
+    }
+}
+
+
+fn lock_cache(cache: &Mutex<LruCache>) -> io::Result<MutexGuard<'_, LruCache>> {
+    cache.lock()
+        .map_err(|_| io::Error::other("Storage cache lock poisoned"))
+}
+
+
+/// Writer invalidating the cache once the wrapped writer completed the object
+struct CachedWriter {
+    inner: ObjectWriter,
+    cache: Arc<Mutex<LruCache>>,
+    name: String,
+}
+
+impl AsyncWrite for CachedWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let res = futures::ready!(Pin::new(&mut this.inner).poll_shutdown(cx));
+
+        lock_cache(&this.cache)?.invalidate(&this.name);
+        Poll::Ready(res)
+    }
+}
+
+
+#[async_trait]
+impl<S> AccessStorage for CachedStorage<S>
+    where
+        S: AccessStorage + Send + Sync
+{
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let key = CacheKey::List(dir_name.as_str().to_string());
+        let (cached, generation) = self.lookup(&key)?;
+        if let Some(CacheValue::List(names)) = cached {
+            return Ok(names);
+        }
+
+        let names: Vec<String> = self.inner.list(dir_name).await?.into_iter().collect();
+        self.store(key, CacheValue::List(names.clone()), generation)?;
+        Ok(names)
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let key = CacheKey::Entries(dir_name.as_str().to_string());
+        let (cached, generation) = self.lookup(&key)?;
+        if let Some(CacheValue::Entries(entries)) = cached {
+            return Ok(entries);
+        }
+
+        let entries = self.inner.list_entries(dir_name).await?;
+        self.store(key, CacheValue::Entries(entries.clone()), generation)?;
+        Ok(entries)
+    }
+
+
+    /// Walk the wrapped storage, which knows how to handle symlinks
+    async fn walk(&self, start: ObjectName<'_>, options: &WalkOptions) -> IdxResult<Vec<ObjectNameBuf>> {
+        self.inner.walk(start, options).await
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let key = CacheKey::Object(obj_name.as_str().to_string());
+        let (cached, generation) = self.lookup(&key)?;
+        if let Some(CacheValue::Bytes(data)) = cached {
+            return Ok(data);
+        }
+
+        let data = self.inner.read_bytes(obj_name).await?;
+        self.store(key, CacheValue::Bytes(data.clone()), generation)?;
+        Ok(data)
+    }
+
+
+    /// Serve cached objects from memory and stream everything else from the
+    /// wrapped storage without caching it
+    async fn open_read(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectReader> {
+        let key = CacheKey::Object(obj_name.as_str().to_string());
+        if let (Some(CacheValue::Bytes(data)), _) = self.lookup(&key)? {
+            return Ok(Box::new(io::Cursor::new(data)));
+        }
+
+        self.inner.open_read(obj_name).await
+    }
+
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let writer = self.inner.open_write(obj_name).await?;
+        self.invalidate(obj_name)?;
+
+        Ok(Box::new(CachedWriter {
+            inner: writer,
+            cache: self.cache.clone(),
+            name: obj_name.as_str().to_string(),
+        }))
+    }
+
+
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let key = CacheKey::Stat(obj_name.as_str().to_string(), with_digest);
+        let (cached, generation) = self.lookup(&key)?;
+        if let Some(CacheValue::Metadata(metadata)) = cached {
+            return Ok(metadata);
+        }
+
+        let metadata = self.inner.stat(obj_name, with_digest).await?;
+        self.store(key, CacheValue::Metadata(metadata.clone()), generation)?;
+        Ok(metadata)
+    }
+
+
+    async fn content_digest(&self, obj_name: ObjectName<'_>) -> IdxResult<Option<ContentDigest>> {
+        let key = CacheKey::Digest(obj_name.as_str().to_string());
+        let (cached, generation) = self.lookup(&key)?;
+        if let Some(CacheValue::Digest(digest)) = cached {
+            return Ok(digest);
+        }
+
+        let digest = self.inner.content_digest(obj_name).await?;
+        self.store(key, CacheValue::Digest(digest), generation)?;
+        Ok(digest)
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        let res = self.inner.write_bytes(name, data).await;
+        self.invalidate(name)?;
+        res
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        self.inner.exists(obj_name).await
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let res = self.inner.delete(obj_name).await;
+        self.invalidate(obj_name)?;
+        res
+    }
+
+
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let res = self.inner.rename(from, to).await;
+        self.invalidate(from)?;
+        self.invalidate(to)?;
+        res
+    }
+
+
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let res = self.inner.copy(from, to).await;
+        self.invalidate(to)?;
+        res
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        let res = self.inner.create_dir(dir_name).await;
+        self.invalidate(dir_name)?;
+        res
+    }
+}
@@ -0,0 +1,248 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use super::transform::TransformWriter;
+use crate::error::*;
+use crate::format::{Compression,DEFAULT_MAX_DECOMPRESSED_SIZE,compress,decompress};
+use crate::ObjectNameBuf;
+
+use std::io;
+use async_trait::async_trait;
+
+/// Storage wrapper compressing objects on write and decompressing them on read
+///
+/// Objects are stored under their name with a suffix appended, `.gz` or
+/// `.zst` by default. The suffix is hidden from listings, and stored objects
+/// without it are left out and can't be accessed. Directories keep their
+/// names.
+///
+/// The default `walk` is used, so symlinks are never descended into.
+#[derive(Clone)]
+pub struct CompressedStorage<S> {
+    inner: S,
+    compression: Compression,
+    level: Option<u32>,
+    suffix: String,
+    max_size: u64,
+}
+
+
+impl<S> CompressedStorage<S> {
+    pub fn new(inner: S, compression: Compression) -> Self {
+        Self {
+            inner,
+            compression,
+            level: None,
+            suffix: compression.extension().to_string(),
+            max_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    /// Select the compression level, the meaning depends on the algorithm
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    /// Select the suffix of stored objects, an empty suffix keeps names as they are
+    pub fn suffix(mut self, suffix: impl Into<String>) -> Self {
+        self.suffix = suffix.into();
+        self
+    }
+
+    /// Select the largest size an object may decompress to, reading larger
+    /// ones fails with a codec error
+    ///
+    /// Defaults to `DEFAULT_MAX_DECOMPRESSED_SIZE`.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Access the wrapped storage, which sees the compressed data
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Name an object is stored under in the wrapped storage
+    fn stored_name(&self, name: ObjectName<'_>) -> IdxResult<ObjectNameBuf> {
+        ObjectNameBuf::from_path(format!("{}{}", name.as_str(), self.suffix))
+    }
+
+    /// Name a stored object is presented under, if it carries the suffix
+    fn visible_name(&self, stored: ObjectName<'_>) -> Option<ObjectNameBuf> {
+        stored.as_str()
+            .strip_suffix(self.suffix.as_str())
+            .and_then(|name| ObjectNameBuf::from_path(name).ok())
+    }
+}
+
+
+impl<S> CompressedStorage<S>
+    where
+        S: AccessStorage + Send + Sync
+{
+    /// Check whether a name is used as it is in the wrapped storage, which is
+    /// the case for the directories and symlinks `list_entries` passes through
+    async fn keeps_name(&self, name: ObjectName<'_>) -> IdxResult<bool> {
+        if name.as_str().is_empty() {
+            return Ok(true);
+        }
+        if !self.inner.exists(name).await? {
+            return Ok(false);
+        }
+
+        let parent = match name.as_str().rfind('/') {
+            Some(pos) => ObjectName::from_path(&name.as_str()[..pos])?,
+            None => ObjectName::empty(),
+        };
+        let entries = self.inner.list_entries(parent).await?;
+
+        Ok(entries.iter().any(|entry| {
+            entry.name() == name && matches!(entry.kind(), EntryKind::Dir | EntryKind::Symlink)
+        }))
+    }
+}
+
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+
+#[async_trait]
+impl<S> AccessStorage for CompressedStorage<S>
+    where
+        S: AccessStorage + Send + Sync
+{
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    /// List directories and the objects carrying the suffix
+    ///
+    /// Symlinks without the suffix may point to directories, so they are kept.
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let entries = self.inner.list_entries(dir_name).await?;
+
+        Ok(entries.into_iter()
+            .filter_map(|entry| match entry.kind() {
+                EntryKind::Dir => Some(entry),
+                kind => match self.visible_name(entry.name()) {
+                    Some(name) => Some(DirEntry::new(name, kind)),
+                    None if kind == EntryKind::Symlink => Some(entry),
+                    None => None,
+                }
+            })
+            .collect())
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let stored = self.stored_name(obj_name)?;
+        let data = self.inner.read_bytes(stored.name()).await?;
+
+        decompress(self.compression, &data, self.max_size)
+    }
+
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let stored = self.stored_name(obj_name)?;
+        let writer = self.inner.open_write(stored.name()).await?;
+
+        let (compression, level) = (self.compression, self.level);
+
+        Ok(Box::new(TransformWriter::new(writer, move |data| compress(compression, level, data))))
+    }
+
+
+    /// Query metadata of the uncompressed object
+    ///
+    /// This reads and decompresses the whole object to find its size.
+    /// Directories keep the metadata of the wrapped storage.
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let stored = self.stored_name(obj_name)?;
+        let metadata = match self.inner.stat(stored.name(), false).await {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.is_not_found() => None,
+            Err(e) => return Err(e),
+        };
+        let modified = match metadata {
+            Some(metadata) => metadata.modified(),
+            None if self.keeps_name(obj_name).await? => return self.inner.stat(obj_name, false).await,
+            None => return Err(not_found(obj_name)),
+        };
+
+        let byte_data = self.read_bytes(obj_name).await?;
+        let digest = if with_digest {
+            Some(ContentDigest::of(&byte_data))
+        } else {
+            None
+        };
+
+        Ok(ObjectMetadata::new(byte_data.len() as u64, modified, digest))
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        let stored = self.stored_name(name)?;
+        let compressed = compress(self.compression, self.level, data.as_ref())?;
+
+        self.inner.write_bytes(stored.name(), compressed).await
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        let stored = self.stored_name(obj_name)?;
+
+        Ok(self.inner.exists(stored.name()).await? || self.keeps_name(obj_name).await?)
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let stored = self.stored_name(obj_name)?;
+
+        if self.inner.exists(stored.name()).await? {
+            self.inner.delete(stored.name()).await
+        } else if self.keeps_name(obj_name).await? {
+            self.inner.delete(obj_name).await
+        } else {
+            Err(not_found(obj_name))
+        }
+    }
+
+
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let from_stored = self.stored_name(from)?;
+
+        if self.inner.exists(from_stored.name()).await? {
+            let to_stored = self.stored_name(to)?;
+            self.inner.rename(from_stored.name(), to_stored.name()).await
+        } else if self.keeps_name(from).await? {
+            self.inner.rename(from, to).await
+        } else {
+            Err(not_found(from))
+        }
+    }
+
+
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let from_stored = self.stored_name(from)?;
+        let to_stored = self.stored_name(to)?;
+
+        self.inner.copy(from_stored.name(), to_stored.name()).await
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        self.inner.create_dir(dir_name).await
+    }
+}
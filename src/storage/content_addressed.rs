@@ -0,0 +1,288 @@
+use super::{AccessStorage,ObjectName,ObjectReader,ObjectWriter,WalkOptions,DirEntry,ObjectMetadata,ContentDigest};
+use super::transform::CommitWriter;
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::collections::HashSet;
+use async_trait::async_trait;
+
+const BLOB_DIR: &str = "blobs";
+const REF_DIR: &str = "refs";
+
+/// Storage wrapper keeping every distinct content only once
+///
+/// Contents are stored as blobs named by their digest, sharded by the first
+/// two bytes of the digest, like `blobs/ab/cd/abcd…`. The object names are
+/// kept separately as small objects below `refs/`, each holding the digest
+/// of its contents in hex. Identical objects share a blob, and copying an
+/// object or taking a `snapshot` of a directory only copies the references.
+///
+/// Blobs are not removed when the last reference to them goes away, this is
+/// left to `gc`.
+#[derive(Clone)]
+pub struct ContentAddressedStorage<S> {
+    inner: S,
+}
+
+
+impl<S> ContentAddressedStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner
+        }
+    }
+
+    /// Access the wrapped storage, which holds the blobs and references
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+
+fn ref_name(name: ObjectName<'_>) -> IdxResult<ObjectNameBuf> {
+    if name.as_str().is_empty() {
+        ObjectNameBuf::from_str(REF_DIR)
+    } else {
+        ObjectNameBuf::from_path(format!("{}/{}", REF_DIR, name.as_str()))
+    }
+}
+
+fn blob_name(digest: &ContentDigest) -> IdxResult<ObjectNameBuf> {
+    let hex = digest.to_string();
+
+    ObjectNameBuf::from_path(format!("{}/{}/{}/{}", BLOB_DIR, &hex[..2], &hex[2..4], hex))
+}
+
+fn parse_ref(name: ObjectName<'_>, data: &[u8]) -> IdxResult<ContentDigest> {
+    std::str::from_utf8(data).ok()
+        .and_then(|hex| ContentDigest::from_hex(hex.trim()))
+        .ok_or_else(|| IdxError::storage_error_msg(format!("Invalid content reference: '{}'", name.as_str())))
+}
+
+
+impl<S> ContentAddressedStorage<S>
+    where
+        S: AccessStorage + Clone + Send + Sync + 'static
+{
+    /// Digest of the contents of an object
+    pub async fn digest(&self, obj_name: ObjectName<'_>) -> IdxResult<ContentDigest> {
+        let reference = ref_name(obj_name)?;
+        let data = self.inner.read_bytes(reference.name()).await?;
+
+        parse_ref(obj_name, &data)
+    }
+
+    /// Store the contents as a blob unless it exists, then point the name at it
+    async fn store(&self, name: ObjectName<'_>, data: &[u8]) -> IdxResult<()> {
+        let digest = ContentDigest::of(data);
+        let blob = blob_name(&digest)?;
+
+        if !self.inner.exists(blob.name()).await? {
+            self.inner.write_bytes(blob.name(), data).await?;
+        }
+
+        let reference = ref_name(name)?;
+        self.inner.write_bytes(reference.name(), digest.to_string()).await
+    }
+
+    /// Copy all objects below the directory `from` to the new directory `to`
+    ///
+    /// Only the references are copied, the snapshot shares all blobs with the
+    /// original. Later changes to either directory don't affect the other.
+    pub async fn snapshot(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        if self.exists(to).await? {
+            let msg = format!("Object exists: '{}'", to.as_str());
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, msg).into());
+        }
+
+        let from_dir = ref_name(from)?;
+        let to_dir = ref_name(to)?;
+        let options = WalkOptions::new().recursive().include_dirs(false);
+        for reference in self.inner.walk(from_dir.name(), &options).await? {
+            let name = reference.name();
+            let rest = &name.as_str()[from_dir.name().as_str().len()..];
+            let target = ObjectNameBuf::from_path(format!("{}{}", to_dir.name().as_str(), rest))?;
+            let data = self.inner.read_bytes(reference.name()).await?;
+            self.inner.write_bytes(target.name(), data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Delete all blobs no object refers to any more, returning their number
+    ///
+    /// This must not run concurrently with writes to the storage. A blob
+    /// stored after the references were collected looks unused, and would be
+    /// deleted right after a new reference started pointing at it.
+    pub async fn gc(&self) -> IdxResult<usize> {
+        let options = WalkOptions::new().recursive().include_dirs(false);
+        let refs = ObjectNameBuf::from_str(REF_DIR)?;
+        let blobs = ObjectNameBuf::from_str(BLOB_DIR)?;
+
+        let mut used = HashSet::new();
+        if self.inner.exists(refs.name()).await? {
+            for reference in self.inner.walk(refs.name(), &options).await? {
+                let data = self.inner.read_bytes(reference.name()).await?;
+                used.insert(parse_ref(reference.name(), &data)?.to_string());
+            }
+        }
+
+        let mut removed = 0;
+        if self.inner.exists(blobs.name()).await? {
+            for blob in self.inner.walk(blobs.name(), &options).await? {
+                let name = blob.name();
+                let hex = name.as_str().rsplit('/').next().unwrap_or_default();
+                if !used.contains(hex) {
+                    self.inner.delete(blob.name()).await?;
+                    removed += 1;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+
+#[async_trait]
+impl<S> AccessStorage for ContentAddressedStorage<S>
+    where
+        S: AccessStorage + Clone + Send + Sync + 'static
+{
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let reference = ref_name(dir_name)?;
+        let entries = match self.inner.list_entries(reference.name()).await {
+            Ok(entries) => entries,
+            // nothing was stored yet
+            Err(e) if e.is_not_found() && dir_name.as_str().is_empty() => vec![],
+            Err(e) => return Err(e),
+        };
+
+        entries.into_iter()
+            .map(|entry| {
+                let name = ObjectNameBuf::from_path(&entry.name().as_str()[REF_DIR.len() + 1..])?;
+                Ok(DirEntry::new(name, entry.kind()))
+            })
+            .collect()
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let digest = self.digest(obj_name).await?;
+        let blob = blob_name(&digest)?;
+
+        self.inner.read_bytes(blob.name()).await
+    }
+
+
+    async fn open_read(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectReader> {
+        let digest = self.digest(obj_name).await?;
+        let blob = blob_name(&digest)?;
+
+        self.inner.open_read(blob.name()).await
+    }
+
+
+    /// Open a writer that stores the object once it is shut down, as the
+    /// digest is only known then
+    ///
+    /// Dropping the writer without shutting it down discards the data.
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let storage = self.clone();
+        let name = ObjectNameBuf::from(obj_name);
+
+        Ok(Box::new(CommitWriter::new(move |data| async move {
+            storage.store(name.name(), &data).await
+        })))
+    }
+
+
+    /// Query metadata without reading the object, the digest is always known
+    /// and always included
+    ///
+    /// The modification time is the time the name was last pointed at new
+    /// contents.
+    async fn stat(&self, obj_name: ObjectName<'_>, _with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let reference = ref_name(obj_name)?;
+        let metadata = self.inner.stat(reference.name(), false).await?;
+        let data = match self.inner.read_bytes(reference.name()).await {
+            Ok(data) => data,
+            // directories keep the metadata of the wrapped storage, they are
+            // the only references that can't be read but listed
+            Err(e) => return match self.inner.list_entries(reference.name()).await {
+                Ok(_) => Ok(metadata),
+                Err(_) => Err(e),
+            },
+        };
+        let digest = parse_ref(obj_name, &data)?;
+
+        let blob = blob_name(&digest)?;
+        let blob = self.inner.stat(blob.name(), false).await?;
+        Ok(ObjectMetadata::new(blob.size(), metadata.modified(), Some(digest)))
+    }
+
+
+    async fn content_digest(&self, obj_name: ObjectName<'_>) -> IdxResult<Option<ContentDigest>> {
+        Ok(Some(self.digest(obj_name).await?))
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        self.store(name, data.as_ref()).await
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        if obj_name.as_str().is_empty() {
+            return Ok(true);
+        }
+
+        let reference = ref_name(obj_name)?;
+        self.inner.exists(reference.name()).await
+    }
+
+
+    /// Delete an object, its blob stays until the next `gc`
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let reference = ref_name(obj_name)?;
+        self.inner.delete(reference.name()).await
+    }
+
+
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let (from, to) = (ref_name(from)?, ref_name(to)?);
+        self.inner.rename(from.name(), to.name()).await
+    }
+
+
+    /// Copy an object by copying its reference
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let (from, to) = (ref_name(from)?, ref_name(to)?);
+        self.inner.copy(from.name(), to.name()).await
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        let refs = ObjectNameBuf::from_str(REF_DIR)?;
+        if !self.inner.exists(refs.name()).await? {
+            self.inner.create_dir(refs.name()).await?;
+        }
+
+        let reference = ref_name(dir_name)?;
+        self.inner.create_dir(reference.name()).await
+    }
+}
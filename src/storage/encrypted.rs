@@ -0,0 +1,286 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,WalkOptions,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use super::transform::TransformWriter;
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::io;
+use async_trait::async_trait;
+use chacha20poly1305::{XChaCha20Poly1305,XNonce};
+use chacha20poly1305::aead::{Aead,AeadCore,KeyInit,OsRng,Payload};
+
+const MAGIC: &[u8; 4] = b"IDXE";
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = MAGIC.len() + 1 + NONCE_LEN;
+const TAG_LEN: usize = 16;
+
+/// Storage wrapper encrypting every object with XChaCha20-Poly1305
+///
+/// Each stored object starts with a header holding a format version and a
+/// random nonce, followed by the ciphertext and the authentication tag. The
+/// header and the object name are authenticated along with the contents.
+/// Objects that were modified, moved to another name, or were encrypted with
+/// a different key, fail to read with `IdxError::Tampered`.
+///
+/// Names and the directory structure are not encrypted. As objects are bound
+/// to their names, `rename` and `copy` decrypt and encrypt them again, for
+/// renamed directories this applies to every object below them.
+#[derive(Clone)]
+pub struct EncryptedStorage<S> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+}
+
+
+impl<S> EncryptedStorage<S> {
+    pub fn new(inner: S, key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// Create a random key from the operating system's random number generator
+    pub fn generate_key() -> [u8; 32] {
+        XChaCha20Poly1305::generate_key(&mut OsRng).into()
+    }
+
+    /// Access the wrapped storage, which sees the encrypted data
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+}
+
+
+/// Data authenticated along with the contents, binding them to their name
+fn associated_data(header: &[u8], name: ObjectName<'_>) -> Vec<u8> {
+    let mut rv = header.to_vec();
+    rv.extend_from_slice(name.as_str().as_bytes());
+    rv
+}
+
+
+fn encrypt(cipher: &XChaCha20Poly1305, name: ObjectName<'_>, data: &[u8]) -> IdxResult<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let mut rv = Vec::with_capacity(HEADER_LEN + data.len() + TAG_LEN);
+    rv.extend_from_slice(MAGIC);
+    rv.push(VERSION);
+    rv.extend_from_slice(&nonce);
+
+    let aad = associated_data(&rv, name);
+    let ciphertext = cipher.encrypt(&nonce, Payload { msg: data, aad: &aad })
+        .map_err(|_| IdxError::storage_error_msg("Failed to encrypt object"))?;
+    rv.extend_from_slice(&ciphertext);
+    Ok(rv)
+}
+
+
+fn decrypt(cipher: &XChaCha20Poly1305, name: ObjectName<'_>, data: &[u8]) -> IdxResult<Vec<u8>> {
+    let tampered = || IdxError::Tampered(name.as_str().to_string());
+
+    if data.len() < HEADER_LEN + TAG_LEN || !data.starts_with(MAGIC) || data[MAGIC.len()] != VERSION {
+        return Err(tampered());
+    }
+
+    let (header, ciphertext) = data.split_at(HEADER_LEN);
+    let nonce = XNonce::from_slice(&header[MAGIC.len() + 1..]);
+
+    let aad = associated_data(header, name);
+    cipher.decrypt(nonce, Payload { msg: ciphertext, aad: &aad })
+        .map_err(|_| tampered())
+}
+
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+fn already_exists(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("Object exists: '{}'", name.as_str())).into()
+}
+
+
+impl<S> EncryptedStorage<S>
+    where
+        S: AccessStorage + Send + Sync
+{
+    /// Kind of the entry with the given name, if there is one
+    async fn kind(&self, name: ObjectName<'_>) -> IdxResult<Option<EntryKind>> {
+        let parent = match name.as_str().rfind('/') {
+            Some(pos) => ObjectName::from_path(&name.as_str()[..pos])?,
+            None => ObjectName::empty(),
+        };
+        let entries = match self.inner.list_entries(parent).await {
+            Ok(entries) => entries,
+            Err(e) if e.is_not_found() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        Ok(entries.iter()
+            .find(|entry| entry.name() == name)
+            .map(|entry| entry.kind()))
+    }
+
+    /// Encrypt an object again for its new name
+    async fn reencrypt(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let data = self.read_bytes(from).await?;
+        self.write_bytes(to, data).await
+    }
+
+    /// Move a directory with everything below it, encrypting every object
+    /// again for its new name
+    async fn rename_dir(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        // directories in the order they were found, so parents come first
+        let mut dirs = vec![ObjectNameBuf::from(from)];
+        let mut files = vec![];
+        let mut pos = 0;
+        while pos < dirs.len() {
+            let dir = dirs[pos].clone();
+            let entries = self.inner.list_entries(dir.name()).await?;
+            for entry in entries {
+                match entry.kind() {
+                    EntryKind::Dir => dirs.push(entry.into_name()),
+                    _ => files.push(entry.into_name()),
+                }
+            }
+            pos += 1;
+        }
+
+        let moved = |name: &ObjectNameBuf| {
+            let name = name.name();
+            let rest = &name.as_str()[from.as_str().len()..];
+            ObjectNameBuf::from_path(format!("{}{}", to.as_str(), rest))
+        };
+
+        for dir in dirs.iter() {
+            let target = moved(dir)?;
+            self.inner.create_dir(target.name()).await?;
+        }
+        for file in files.iter() {
+            let target = moved(file)?;
+            self.reencrypt(file.name(), target.name()).await?;
+            self.inner.delete(file.name()).await?;
+        }
+        for dir in dirs.iter().rev() {
+            match self.inner.delete(dir.name()).await {
+                // some storages drop directories along with their last object
+                Err(e) if !e.is_not_found() => return Err(e),
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+#[async_trait]
+impl<S> AccessStorage for EncryptedStorage<S>
+    where
+        S: AccessStorage + Send + Sync
+{
+    type ListIntoIter = S::ListIntoIter;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        self.inner.list(dir_name).await
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        self.inner.list_entries(dir_name).await
+    }
+
+
+    async fn walk(&self, start: ObjectName<'_>, options: &WalkOptions) -> IdxResult<Vec<ObjectNameBuf>> {
+        self.inner.walk(start, options).await
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let data = self.inner.read_bytes(obj_name).await?;
+
+        decrypt(&self.cipher, obj_name, &data)
+    }
+
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let writer = self.inner.open_write(obj_name).await?;
+        let cipher = self.cipher.clone();
+        let name = ObjectNameBuf::from(obj_name);
+
+        Ok(Box::new(TransformWriter::new(writer, move |data| encrypt(&cipher, name.name(), data))))
+    }
+
+
+    /// Query metadata of the decrypted object
+    ///
+    /// The size is known from the stored size, but the digest requires
+    /// reading and decrypting the whole object.
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let stored = self.inner.stat(obj_name, false).await?;
+
+        if with_digest {
+            let byte_data = self.read_bytes(obj_name).await?;
+            let digest = ContentDigest::of(&byte_data);
+            Ok(ObjectMetadata::new(byte_data.len() as u64, stored.modified(), Some(digest)))
+        } else {
+            let size = stored.size().saturating_sub((HEADER_LEN + TAG_LEN) as u64);
+            Ok(ObjectMetadata::new(size, stored.modified(), None))
+        }
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        let encrypted = encrypt(&self.cipher, name, data.as_ref())?;
+
+        self.inner.write_bytes(name, encrypted).await
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        self.inner.exists(obj_name).await
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        self.inner.delete(obj_name).await
+    }
+
+
+    /// Move an object or directory, encrypting the objects again as they
+    /// are bound to their names
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        if self.inner.exists(to).await? {
+            return Err(already_exists(to));
+        }
+
+        let kind = self.kind(from).await?;
+        match kind {
+            None => Err(not_found(from)),
+            Some(EntryKind::Dir) => self.rename_dir(from, to).await,
+            Some(_) => {
+                self.reencrypt(from, to).await?;
+                self.inner.delete(from).await
+            }
+        }
+    }
+
+
+    /// Copy an object, encrypting it again as it is bound to its name
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        if self.inner.exists(to).await? {
+            return Err(already_exists(to));
+        }
+
+        self.reencrypt(from, to).await
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        self.inner.create_dir(dir_name).await
+    }
+}
@@ -0,0 +1,48 @@
+use crate::{ObjectName,ObjectNameBuf};
+
+/// The kind of object a directory entry refers to
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+
+/// Typed entry returned by `AccessStorage::list_entries`
+#[derive(Clone,Debug)]
+pub struct DirEntry {
+    name: ObjectNameBuf,
+    kind: EntryKind,
+}
+
+impl DirEntry {
+    pub fn new(name: ObjectNameBuf, kind: EntryKind) -> Self {
+        Self {
+            name,
+            kind
+        }
+    }
+
+    /// Name of the entry relative to the storage root
+    pub fn name(&self) -> ObjectName<'_> {
+        self.name.name()
+    }
+
+    pub fn into_name(self) -> ObjectNameBuf {
+        self.name
+    }
+
+    pub fn kind(&self) -> EntryKind {
+        self.kind
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Dir
+    }
+}
@@ -1,28 +1,274 @@
-use super::{AccessStorage,ObjectName};
+mod atomic;
+#[cfg(feature = "watch")]
+pub(crate) mod watch;
+
+use super::{AccessStorage,ObjectName,ObjectReader,ObjectWriter,WalkOptions,DirEntry,EntryKind,ObjectMetadata};
+use super::metadata::DigestBuilder;
+use super::walk::SymlinkPolicy;
 use crate::error::*;
+use crate::ObjectNameBuf;
 
+use std::ffi::{OsStr,OsString};
+use std::io;
 use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
 use async_trait::async_trait;
 use tokio::fs;
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+
+/// What `FileStorage` does with file names that aren't valid unicode
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum NonUnicodePolicy {
+    /// Abort the listing with an error
+    Fail,
+    /// Leave the entry out of listings and remember it for `take_skipped`,
+    /// up to `FileStorage::MAX_SKIPPED` paths
+    Skip,
+    /// Represent all names in the escaped form of `ObjectNameBuf::escape`
+    ///
+    /// Names passed to the storage are unescaped as well, so a file named
+    /// `50%` has to be accessed as `50%25`.
+    Escape,
+}
 
+
+/// Storage for objects in a directory of the local filesystem
+///
+/// By default, writes go to a temporary file in the target directory, which is
+/// synced and renamed into place once complete. Concurrent readers and crashes
+/// therefore never see partially written objects. Missing parent directories
+/// are created on write.
+///
+/// Object names can't contain dot segments, so they always point below the
+/// base path. Symlinks inside the base path may still lead elsewhere, unless
+/// `confine_symlinks` is enabled.
 #[derive(Clone)]
 pub struct FileStorage {
     base_path: PathBuf,
+    atomic_writes: bool,
+    sync_dir: bool,
+    create_dirs: bool,
+    confine_symlinks: bool,
+    non_unicode: NonUnicodePolicy,
+    skipped: Arc<Mutex<Vec<PathBuf>>>,
 }
 
 
 impl FileStorage {
+    /// Number of skipped paths remembered until `take_skipped` is called
+    pub const MAX_SKIPPED: usize = 1024;
+
     pub fn new(path: impl Into<PathBuf>) -> Self {
         Self {
             base_path: path.into(),
+            atomic_writes: true,
+            sync_dir: false,
+            create_dirs: true,
+            confine_symlinks: false,
+            non_unicode: NonUnicodePolicy::Fail,
+            skipped: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Select whether writes go through a temporary file and a rename
+    pub fn atomic_writes(mut self, enable: bool) -> Self {
+        self.atomic_writes = enable;
+        self
+    }
+
+    /// Select whether the directory is synced after an atomic write, so that
+    /// the rename itself survives a crash
+    pub fn sync_dir(mut self, enable: bool) -> Self {
+        self.sync_dir = enable;
+        self
+    }
+
+    /// Select whether missing parent directories are created on write
+    pub fn create_dirs(mut self, enable: bool) -> Self {
+        self.create_dirs = enable;
+        self
+    }
+
+    /// Select whether paths resolving outside the base path through symlinks
+    /// are refused with `IdxError::SymlinkEscape`
+    ///
+    /// Symlinks leading outside are also left out of listings and walks, as
+    /// they can't be accessed anyway.
+    pub fn confine_symlinks(mut self, enable: bool) -> Self {
+        self.confine_symlinks = enable;
+        self
+    }
+
+    /// Select how file names that aren't valid unicode are handled
+    pub fn non_unicode(mut self, policy: NonUnicodePolicy) -> Self {
+        self.non_unicode = policy;
+        self
+    }
+
+    /// Return and forget the paths left out of listings so far
+    ///
+    /// Only `NonUnicodePolicy::Skip` leaves out paths. Every listing adds the
+    /// paths it skips again, and once `MAX_SKIPPED` paths are remembered,
+    /// further ones are left out silently, so call this after each listing.
+    pub fn take_skipped(&self) -> Vec<PathBuf> {
+        match self.skipped.lock() {
+            Ok(mut skipped) => std::mem::take(&mut *skipped),
+            Err(_) => vec![],
         }
     }
 
-    fn make_path(&self, obj: ObjectName<'_>) -> PathBuf {
+    async fn prepare_write(&self, path: &Path) -> IdxResult<()> {
+        if self.create_dirs {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn make_path(&self, obj: ObjectName<'_>) -> IdxResult<PathBuf> {
         let mut p = self.base_path.clone();
-        p.push(Path::new(obj.as_str()));
-        p
+        if self.non_unicode == NonUnicodePolicy::Escape {
+            p.push(os_string_from_bytes(obj.unescape()?)?);
+        } else {
+            p.push(Path::new(obj.as_str()));
+        }
+        Ok(p)
+    }
+
+    /// Make the path for an object and check that it stays below the base path
+    ///
+    /// With `follow_last` unset, only the directories leading to the object
+    /// are checked, so a symlink itself can still be deleted or renamed.
+    ///
+    /// The path is checked before it is used, and the filesystem isn't locked
+    /// in between. Whoever can change the symlinks below the base path may
+    /// swap one in after the check, so the confinement only protects against
+    /// links that are already in place.
+    async fn resolve(&self, obj: ObjectName<'_>, follow_last: bool) -> IdxResult<PathBuf> {
+        let path = self.make_path(obj)?;
+        if !self.confine_symlinks {
+            return Ok(path);
+        }
+
+        let root = fs::canonicalize(&self.base_path).await?;
+        let mut existing = if follow_last {
+            path.as_path()
+        } else {
+            path.parent().unwrap_or(&path)
+        };
+
+        // objects about to be created don't exist yet, check their closest ancestor
+        loop {
+            match fs::symlink_metadata(existing).await {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    existing = existing.parent().unwrap_or(&root);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // dangling symlinks can't be resolved and are refused as well
+        match fs::canonicalize(existing).await {
+            Ok(real_path) if real_path.starts_with(&root) => Ok(path),
+            Ok(_) => Err(IdxError::SymlinkEscape(path)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(IdxError::SymlinkEscape(path)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Canonical base path to check symlinks against, if they are confined
+    async fn confinement_root(&self) -> IdxResult<Option<PathBuf>> {
+        if self.confine_symlinks {
+            Ok(Some(fs::canonicalize(&self.base_path).await?))
+        } else {
+            Ok(None)
+        }
     }
+
+    /// Turn a path below the base path into a name string
+    ///
+    /// Returns `None` if the entry is skipped because of its name, which
+    /// includes the temporary files of atomic writes.
+    fn relative_str(&self, path: &Path) -> IdxResult<Option<String>> {
+        if atomic::is_temp_path(path) {
+            return Ok(None);
+        }
+
+        let rel_path = path.strip_prefix(&self.base_path)
+            .map_err(IdxError::storage_error)?;
+
+        let mut segments = vec![];
+        for component in rel_path.components() {
+            let os_str = component.as_os_str();
+            let segment = match (os_str.to_str(), self.non_unicode) {
+                (_, NonUnicodePolicy::Escape) => {
+                    let escaped = ObjectNameBuf::escape(&os_str_bytes(os_str))?;
+                    escaped.name().as_str().to_string()
+                }
+                (Some(s), _) => s.to_string(),
+                (None, NonUnicodePolicy::Skip) => {
+                    if let Ok(mut skipped) = self.skipped.lock() {
+                        if skipped.len() < Self::MAX_SKIPPED {
+                            skipped.push(path.to_path_buf());
+                        }
+                    }
+                    return Ok(None);
+                }
+                (None, NonUnicodePolicy::Fail) => {
+                    return Err(IdxError::storage_error_msg("Can't convert filesystem path to unicode string"));
+                }
+            };
+            segments.push(segment);
+        }
+
+        Ok(Some(segments.join("/")))
+    }
+
+    /// Turn a path below the base path into an object name
+    fn relative_name(&self, path: &Path) -> IdxResult<Option<ObjectNameBuf>> {
+        self.relative_str(path)?
+            .map(ObjectNameBuf::from_path)
+            .transpose()
+    }
+}
+
+
+/// Check whether the symlink at `path` leads outside of `root`
+///
+/// Dangling links can't be resolved and count as escaping.
+async fn link_escapes(path: &Path, root: &Path) -> IdxResult<bool> {
+    match fs::canonicalize(path).await {
+        Ok(real_path) => Ok(!real_path.starts_with(root)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+
+#[cfg(unix)]
+fn os_str_bytes(s: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    s.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_str_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> IdxResult<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> IdxResult<OsString> {
+    String::from_utf8(bytes)
+        .map(OsString::from)
+        .map_err(IdxError::storage_error)
 }
 
 
@@ -33,20 +279,133 @@ impl AccessStorage for FileStorage {
     /// Read directory asynchronously using tokio, but not recursively
     async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter>
     {
-        let path = self.make_path(dir_name);
+        let path = self.resolve(dir_name, true).await?;
+        let root = self.confinement_root().await?;
 
         //println!("list({:?} in {:?})", dir_name.as_str(), self.base_path);
         let mut dir = fs::read_dir(path).await?;
         let mut rv = vec![];
         while let Some(entry) = dir.next_entry().await? {
-            let entry_path = entry.path();
-            let entry_rel_path = entry_path.strip_prefix(&self.base_path)
-                .map_err(IdxError::storage_error)?;
+            if let Some(root) = &root {
+                if entry.file_type().await?.is_symlink() && link_escapes(&entry.path(), root).await? {
+                    continue;
+                }
+            }
+
+            if let Some(s) = self.relative_str(&entry.path())? {
+                rv.push(s);
+            }
+        }
+
+        Ok(rv)
+    }
+
+
+    /// Read directory asynchronously and classify entries without following symlinks
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let path = self.resolve(dir_name, true).await?;
+        let root = self.confinement_root().await?;
+
+        let mut dir = fs::read_dir(path).await?;
+        let mut rv = vec![];
+        while let Some(entry) = dir.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let kind = if file_type.is_symlink() {
+                if let Some(root) = &root {
+                    if link_escapes(&entry.path(), root).await? {
+                        continue;
+                    }
+                }
+                EntryKind::Symlink
+            } else if file_type.is_dir() {
+                EntryKind::Dir
+            } else if file_type.is_file() {
+                EntryKind::File
+            } else {
+                EntryKind::Other
+            };
+
+            if let Some(name) = self.relative_name(&entry.path())? {
+                rv.push(DirEntry::new(name, kind));
+            }
+        }
+
+        Ok(rv)
+    }
+
 
-            let s = entry_rel_path.to_str()
-                .ok_or(IdxError::storage_error_msg("Can't convert filesystem path to unicode string"))?
-                .to_string();
-            rv.push(s);
+    /// Walk the directory tree using tokio
+    ///
+    /// When following symlinks, directories already being visited further up
+    /// the tree are not entered again, so link loops terminate.
+    async fn walk(&self, start: ObjectName<'_>, options: &WalkOptions) -> IdxResult<Vec<ObjectNameBuf>> {
+        let mut rv = vec![];
+        if !options.descends_below(0) {
+            return Ok(rv);
+        }
+
+        let follow = options.symlink_policy() == SymlinkPolicy::Follow;
+        let start_path = self.resolve(start, true).await?;
+        // links leading outside of the root are left out when confined
+        let root = self.confinement_root().await?;
+        let ancestors = if follow {
+            vec![fs::canonicalize(&start_path).await?]
+        } else {
+            vec![]
+        };
+
+        let mut pending = vec![(start_path, 0, ancestors)];
+        while let Some((dir_path, depth, ancestors)) = pending.pop() {
+            let mut dir = fs::read_dir(&dir_path).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let entry_path = entry.path();
+                let mut file_type = entry.file_type().await?;
+
+                if file_type.is_symlink() {
+                    if options.symlink_policy() == SymlinkPolicy::Skip {
+                        continue;
+                    }
+
+                    if let Some(root) = &root {
+                        if link_escapes(&entry_path, root).await? {
+                            continue;
+                        }
+                    }
+
+                    if follow {
+                        // dangling links are reported like plain files
+                        if let Ok(meta) = fs::metadata(&entry_path).await {
+                            file_type = meta.file_type();
+                        }
+                    }
+                }
+
+                let name = match self.relative_name(&entry_path)? {
+                    Some(name) => name,
+                    None => continue,
+                };
+                if !file_type.is_dir() {
+                    rv.push(name);
+                    continue;
+                }
+
+                if options.includes_dirs() {
+                    rv.push(name);
+                }
+
+                if options.descends_below(depth + 1) {
+                    let mut entry_ancestors = ancestors.clone();
+                    if follow {
+                        let real_path = fs::canonicalize(&entry_path).await?;
+                        if ancestors.contains(&real_path) {
+                            continue;
+                        }
+                        entry_ancestors.push(real_path);
+                    }
+
+                    pending.push((entry_path, depth + 1, entry_ancestors));
+                }
+            }
         }
 
         Ok(rv)
@@ -54,20 +413,156 @@ impl AccessStorage for FileStorage {
 
 
     async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
-        let path = self.make_path(obj_name);
+        let path = self.resolve(obj_name, true).await?;
 
         let contents = fs::read(&path).await?;
         Ok(contents)
     }
 
 
+    async fn open_read(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectReader> {
+        let path = self.resolve(obj_name, true).await?;
+
+        let file = fs::File::open(&path).await?;
+        Ok(Box::new(file))
+    }
+
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let path = self.resolve(obj_name, true).await?;
+        self.prepare_write(&path).await?;
+
+        if self.atomic_writes {
+            let writer = atomic::AtomicWriter::create(path, self.sync_dir).await?;
+            Ok(Box::new(writer))
+        } else {
+            let file = fs::File::create(&path).await?;
+            Ok(Box::new(file))
+        }
+    }
+
+
+    /// Take size and modification time from the filesystem metadata
+    ///
+    /// The digest is computed by reading the file in chunks, so large files are
+    /// never held in memory completely.
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let path = self.resolve(obj_name, true).await?;
+
+        let meta = fs::metadata(&path).await?;
+        let digest = if with_digest {
+            let mut file = fs::File::open(&path).await?;
+            let mut hasher = DigestBuilder::new();
+            let mut buf = vec![0u8; 64 * 1024];
+
+            loop {
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+
+            Some(hasher.finish())
+        } else {
+            None
+        };
+
+        Ok(ObjectMetadata::new(meta.len(), meta.modified().ok(), digest))
+    }
+
+
     async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
         where
             T: AsRef<[u8]> + Unpin + Send
     {
-        let path = self.make_path(name);
+        let path = self.resolve(name, true).await?;
+        self.prepare_write(&path).await?;
+
+        if !self.atomic_writes {
+            fs::write(&path, data).await?;
+            return Ok(());
+        }
 
-        fs::write(&path, data).await?;
+        let temp = atomic::temp_path(&path);
+        let bytes = data.as_ref();
+        let res = async {
+            let mut file = fs::File::create(&temp).await?;
+            file.write_all(bytes).await?;
+            atomic::commit(file, &temp, &path, self.sync_dir).await
+        }.await;
+
+        if res.is_err() {
+            let _ = fs::remove_file(&temp).await;
+        }
+        Ok(res?)
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        let path = self.resolve(obj_name, false).await?;
+
+        match fs::symlink_metadata(&path).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let path = self.resolve(obj_name, false).await?;
+
+        if fs::symlink_metadata(&path).await?.is_dir() {
+            fs::remove_dir(&path).await?;
+        } else {
+            fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+
+    /// Rename using the filesystem
+    ///
+    /// The check for an existing target and the rename are not atomic, a
+    /// concurrent writer may still create the target in between.
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let from_path = self.resolve(from, false).await?;
+        let to_path = self.resolve(to, false).await?;
+
+        fs::symlink_metadata(&from_path).await?;
+        if self.exists(to).await? {
+            return Err(already_exists(to));
+        }
+
+        fs::rename(&from_path, &to_path).await?;
+        Ok(())
+    }
+
+
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let from_path = self.resolve(from, true).await?;
+        let to_path = self.resolve(to, true).await?;
+
+        fs::metadata(&from_path).await?;
+        if self.exists(to).await? {
+            return Err(already_exists(to));
+        }
+
+        fs::copy(&from_path, &to_path).await?;
         Ok(())
     }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        let path = self.resolve(dir_name, true).await?;
+
+        fs::create_dir(&path).await?;
+        Ok(())
+    }
+}
+
+
+fn already_exists(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("Object exists: '{}'", name.as_str())).into()
 }
@@ -0,0 +1,169 @@
+use std::future::Future;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::pin::Pin;
+use std::process;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::task::{Context,Poll};
+use tokio::fs;
+use tokio::io::{AsyncWrite,AsyncWriteExt};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Pick a unique temporary file name next to `target`
+pub(super) fn temp_path(target: &Path) -> PathBuf {
+    let file_name = target.file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let count = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    target.with_file_name(format!(".{}.{}-{}.tmp", file_name, process::id(), count))
+}
+
+
+/// Check whether a path was picked by `temp_path`
+pub(super) fn is_temp_path(path: &Path) -> bool {
+    let file_name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(file_name) => file_name,
+        None => return false,
+    };
+    let stem = match file_name.strip_prefix('.').and_then(|n| n.strip_suffix(".tmp")) {
+        Some(stem) => stem,
+        None => return false,
+    };
+
+    match stem.rsplit_once('.').and_then(|(_, suffix)| suffix.split_once('-')) {
+        Some((pid, count)) => {
+            let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+            is_number(pid) && is_number(count)
+        }
+        None => false,
+    }
+}
+
+
+/// Make the data in `file` durable and move it over `target`
+///
+/// The permissions of an existing target are copied to the new file first.
+pub(super) async fn commit(mut file: fs::File, temp: &Path, target: &Path, sync_dir: bool) -> io::Result<()> {
+    match fs::metadata(target).await {
+        Ok(meta) => file.set_permissions(meta.permissions()).await?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    file.flush().await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(temp, target).await?;
+
+    if sync_dir {
+        sync_parent(target).await?;
+    }
+    Ok(())
+}
+
+
+/// Flush the directory entry of `path` to disk
+#[cfg(unix)]
+async fn sync_parent(path: &Path) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+/// Directories can't be opened for syncing on this platform
+#[cfg(not(unix))]
+async fn sync_parent(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+
+type CommitFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+enum WriterState {
+    Writing(fs::File),
+    Committing(CommitFuture),
+    Done,
+}
+
+
+/// Writer to a temporary file that replaces the target on shutdown
+///
+/// Dropping the writer before shutdown completes removes the temporary file
+/// and leaves the target untouched. As `drop` can't wait for a future, the
+/// file is removed with a blocking call on the dropping thread.
+pub(super) struct AtomicWriter {
+    state: WriterState,
+    temp: PathBuf,
+    target: PathBuf,
+    sync_dir: bool,
+}
+
+impl AtomicWriter {
+    pub(super) async fn create(target: PathBuf, sync_dir: bool) -> io::Result<Self> {
+        let temp = temp_path(&target);
+        let file = fs::File::create(&temp).await?;
+
+        Ok(Self {
+            state: WriterState::Writing(file),
+            temp,
+            target,
+            sync_dir,
+        })
+    }
+}
+
+fn not_writing() -> io::Error {
+    io::Error::other("Writer was already shut down")
+}
+
+impl AsyncWrite for AtomicWriter {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().state {
+            WriterState::Writing(file) => Pin::new(file).poll_write(cx, buf),
+            _ => Poll::Ready(Err(not_writing())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().state {
+            WriterState::Writing(file) => Pin::new(file).poll_flush(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if let WriterState::Writing(_) = this.state {
+            if let WriterState::Writing(file) = std::mem::replace(&mut this.state, WriterState::Done) {
+                let temp = this.temp.clone();
+                let target = this.target.clone();
+                let sync_dir = this.sync_dir;
+
+                this.state = WriterState::Committing(Box::pin(async move {
+                    commit(file, &temp, &target, sync_dir).await
+                }));
+            }
+        }
+
+        match &mut this.state {
+            WriterState::Committing(fut) => {
+                let res = futures::ready!(fut.as_mut().poll(cx));
+                this.state = WriterState::Done;
+                Poll::Ready(res)
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Drop for AtomicWriter {
+    fn drop(&mut self) {
+        // a finished commit has already moved the temporary file away
+        let _ = std::fs::remove_file(&self.temp);
+    }
+}
@@ -0,0 +1,114 @@
+use super::FileStorage;
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context,Poll};
+use std::thread;
+use std::time::Duration;
+use futures::Stream;
+use notify::{DebouncedEvent,RecommendedWatcher,RecursiveMode,Watcher};
+use tokio::sync::mpsc::{UnboundedReceiver,UnboundedSender,unbounded_channel};
+
+/// Change to the objects of a watched `FileStorage`
+#[derive(Clone,Debug,PartialEq)]
+pub enum WatchEvent {
+    Created(ObjectNameBuf),
+    Modified(ObjectNameBuf),
+    Deleted(ObjectNameBuf),
+    /// Moved from the first to the second name
+    Renamed(ObjectNameBuf,ObjectNameBuf),
+    /// Events were lost or the watcher failed, anything may have changed
+    Rescan,
+}
+
+
+/// Stream of the changes below the base path of a `FileStorage`
+///
+/// Watching stops when the stream is dropped.
+pub struct Watch {
+    _watcher: RecommendedWatcher,
+    events: UnboundedReceiver<WatchEvent>,
+}
+
+impl Stream for Watch {
+    type Item = WatchEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().events.poll_recv(cx)
+    }
+}
+
+
+impl FileStorage {
+    /// Watch the base path and everything below it for changes
+    ///
+    /// Events for the same object are merged until nothing happened to it
+    /// for the `debounce` period. Temporary files of atomic writes are left
+    /// out. As these writes replace the file, they show up as `Created` even
+    /// if the object existed before, while writes in place show up as
+    /// `Modified`. Renaming a directory is reported once, not for every
+    /// object in it.
+    pub fn watch(&self, debounce: Duration) -> IdxResult<Watch> {
+        // events carry absolute paths, which have to be turned back into names
+        let mut storage = self.clone();
+        if storage.base_path.is_relative() {
+            storage.base_path = std::env::current_dir()?.join(&storage.base_path);
+        }
+
+        let (raw_tx, raw_rx) = mpsc::channel();
+        let mut watcher = notify::watcher(raw_tx, debounce)
+            .map_err(IdxError::storage_error)?;
+        watcher.watch(&storage.base_path, RecursiveMode::Recursive)
+            .map_err(IdxError::storage_error)?;
+
+        let (tx, events) = unbounded_channel();
+        // the watcher reports on a std channel, which ends when it is dropped
+        thread::spawn(move || {
+            for event in raw_rx {
+                if !forward(&storage, event, &tx) {
+                    break;
+                }
+            }
+        });
+
+        Ok(Watch {
+            _watcher: watcher,
+            events,
+        })
+    }
+}
+
+
+/// Pass on an event if it concerns objects, return false once the stream is
+/// gone
+fn forward(storage: &FileStorage, event: DebouncedEvent, tx: &UnboundedSender<WatchEvent>) -> bool {
+    // temporary files of atomic writes have no name
+    let name = |path: &Path| {
+        storage.relative_name(path).ok().flatten()
+            .filter(|name| !name.name().as_str().is_empty())
+    };
+
+    let event = match event {
+        DebouncedEvent::Create(path) => name(&path).map(WatchEvent::Created),
+        DebouncedEvent::Write(path) => name(&path).map(WatchEvent::Modified),
+        DebouncedEvent::Remove(path) => name(&path).map(WatchEvent::Deleted),
+        DebouncedEvent::Rename(from, to) => match (name(&from), name(&to)) {
+            (Some(from), Some(to)) => Some(WatchEvent::Renamed(from, to)),
+            // moved to a name that can't be represented
+            (Some(from), None) => Some(WatchEvent::Deleted(from)),
+            // includes atomic writes, which end with a rename of the temporary file
+            (None, Some(to)) => Some(WatchEvent::Created(to)),
+            _ => None,
+        },
+        DebouncedEvent::Rescan | DebouncedEvent::Error(..) => Some(WatchEvent::Rescan),
+        DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) | DebouncedEvent::Chmod(_) => None,
+    };
+
+    match event {
+        Some(event) => tx.send(event).is_ok(),
+        None => true,
+    }
+}
@@ -0,0 +1,448 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use super::transform::CommitWriter;
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,MutexGuard};
+use async_trait::async_trait;
+use tokio::task::spawn_blocking;
+use git2::{Commit,ErrorCode,FileMode,ObjectType,Oid,Repository,Signature,Tree,TreeEntry};
+use git2::build::TreeUpdateBuilder;
+
+const LINK_MODE: i32 = 0o120000;
+
+/// Storage for the files in the tree of a commit in a local git repository
+///
+/// The work tree and the index of the repository are never looked at or
+/// modified. Storages opened with `at_revision` are fixed to one commit and
+/// read-only. Storages opened with `on_branch` follow the tip of the branch,
+/// and every change through the storage adds a commit to the branch.
+///
+/// Git can't represent empty directories, so `create_dir` fails and a
+/// directory disappears with the last object in it. Tree entries with names
+/// that aren't valid unicode or valid object names are left out of listings.
+///
+/// The work on the repository runs on the blocking thread pool of the
+/// runtime, one operation at a time.
+#[derive(Clone)]
+pub struct GitStorage {
+    repo: Arc<Mutex<Repository>>,
+    revision: Revision,
+    author: Option<(String,String)>,
+}
+
+#[derive(Clone)]
+enum Revision {
+    Commit(Oid),
+    /// Full name of the branch reference
+    Branch(String),
+}
+
+
+impl GitStorage {
+    /// Open the tree of a commit for reading
+    ///
+    /// The revision can be anything git understands, like a branch or tag
+    /// name, a commit id, or an expression like `main~2`.
+    pub fn at_revision(repo_path: impl Into<PathBuf>, revision: &str) -> IdxResult<Self> {
+        let repo = Repository::open(repo_path.into())
+            .map_err(IdxError::storage_error)?;
+        let id = repo.revparse_single(revision)
+            .and_then(|obj| obj.peel_to_commit())
+            .map(|commit| commit.id())
+            .map_err(IdxError::storage_error)?;
+
+        Ok(Self {
+            revision: Revision::Commit(id),
+            repo: Arc::new(Mutex::new(repo)),
+            author: None,
+        })
+    }
+
+    /// Open the tip of a branch for reading and writing
+    ///
+    /// The branch is created with the first write if it doesn't exist.
+    pub fn on_branch(repo_path: impl Into<PathBuf>, branch: &str) -> IdxResult<Self> {
+        let repo = Repository::open(repo_path.into())
+            .map_err(IdxError::storage_error)?;
+
+        Ok(Self {
+            revision: Revision::Branch(format!("refs/heads/{}", branch)),
+            repo: Arc::new(Mutex::new(repo)),
+            author: None,
+        })
+    }
+
+    /// Set name and email recorded in commits
+    ///
+    /// By default these are taken from the git configuration.
+    pub fn author(mut self, name: impl Into<String>, email: impl Into<String>) -> Self {
+        self.author = Some((name.into(), email.into()));
+        self
+    }
+
+    /// Id of the commit currently read from, `None` for a branch without commits
+    pub async fn commit_id(&self) -> IdxResult<Option<String>> {
+        self.with_repo(|storage, repo| {
+            Ok(storage.head(repo)?.map(|commit| commit.id().to_string()))
+        }).await
+    }
+
+    /// Run work on the locked repository on the blocking thread pool
+    async fn with_repo<F,T>(&self, f: F) -> IdxResult<T>
+        where
+            F: FnOnce(&Self, &Repository) -> IdxResult<T> + Send + 'static,
+            T: Send + 'static
+    {
+        let storage = self.clone();
+
+        spawn_blocking(move || {
+            let repo = storage.lock()?;
+            f(&storage, &repo)
+        }).await.map_err(IdxError::storage_error)?
+    }
+
+    fn lock(&self) -> IdxResult<MutexGuard<'_, Repository>> {
+        self.repo.lock()
+            .map_err(|_| IdxError::storage_error_msg("Git repository lock poisoned"))
+    }
+
+    fn head<'r>(&self, repo: &'r Repository) -> IdxResult<Option<Commit<'r>>> {
+        let res = match &self.revision {
+            Revision::Commit(id) => repo.find_commit(*id),
+            Revision::Branch(refname) => repo.find_reference(refname)
+                .and_then(|r| r.peel_to_commit()),
+        };
+
+        match res {
+            Ok(commit) => Ok(Some(commit)),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(IdxError::storage_error(e)),
+        }
+    }
+
+    fn root<'r>(&self, repo: &'r Repository) -> IdxResult<Option<Tree<'r>>> {
+        match self.head(repo)? {
+            Some(commit) => Ok(Some(commit.tree().map_err(IdxError::storage_error)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Find the tree entry of an object, not-found errors are storage errors
+    /// of kind `NotFound`
+    fn entry(&self, repo: &Repository, name: ObjectName<'_>) -> IdxResult<TreeEntry<'static>> {
+        let root = self.root(repo)?
+            .ok_or_else(|| not_found(name))?;
+
+        root.get_path(Path::new(name.as_str()))
+            .map_err(|e| git_error(e, name))
+    }
+
+    /// Find the tree of a directory, the root tree for an empty name
+    fn dir<'r>(&self, repo: &'r Repository, dir_name: ObjectName<'_>) -> IdxResult<Option<Tree<'r>>> {
+        if dir_name.as_str().is_empty() {
+            return self.root(repo);
+        }
+
+        let entry = self.entry(repo, dir_name)?;
+        if entry.kind() != Some(ObjectType::Tree) {
+            return Err(not_a_directory(dir_name));
+        }
+
+        let tree = repo.find_tree(entry.id())
+            .map_err(IdxError::storage_error)?;
+        Ok(Some(tree))
+    }
+
+    fn blob_data(&self, repo: &Repository, name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let entry = self.entry(repo, name)?;
+        if entry.kind() != Some(ObjectType::Blob) {
+            let msg = format!("Is a directory: '{}'", name.as_str());
+            return Err(IdxError::storage_error_msg(msg));
+        }
+
+        let blob = repo.find_blob(entry.id())
+            .map_err(IdxError::storage_error)?;
+        Ok(blob.content().to_vec())
+    }
+
+    fn signature(&self, repo: &Repository) -> IdxResult<Signature<'static>> {
+        let res = match &self.author {
+            Some((name, email)) => Signature::now(name, email),
+            None => repo.signature(),
+        };
+
+        res.map_err(IdxError::storage_error)
+    }
+
+    /// Create a commit on the branch with the tree of the current tip changed
+    /// by `update`
+    async fn commit<F>(&self, name: ObjectName<'_>, message: String, update: F) -> IdxResult<()>
+        where
+            F: FnOnce(&Repository, &Tree<'_>, &mut TreeUpdateBuilder) -> IdxResult<()> + Send + 'static
+    {
+        let refname = match &self.revision {
+            Revision::Branch(refname) => refname.clone(),
+            Revision::Commit(_) => return Err(IdxError::ReadOnly(name.as_str().to_string())),
+        };
+
+        self.with_repo(move |storage, repo| {
+            let parent = storage.head(repo)?;
+            let base = match &parent {
+                Some(commit) => commit.tree(),
+                None => repo.treebuilder(None)
+                    .and_then(|builder| builder.write())
+                    .and_then(|id| repo.find_tree(id)),
+            }.map_err(IdxError::storage_error)?;
+
+            let mut builder = TreeUpdateBuilder::new();
+            update(repo, &base, &mut builder)?;
+            let tree = builder.create_updated(repo, &base)
+                .and_then(|id| repo.find_tree(id))
+                .map_err(IdxError::storage_error)?;
+
+            let signature = storage.signature(repo)?;
+            let parents: Vec<_> = parent.iter().collect();
+            repo.commit(Some(&refname), &signature, &signature, &message, &tree, &parents)
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }).await
+    }
+
+    async fn write_blob(&self, name: ObjectNameBuf, data: Vec<u8>) -> IdxResult<()> {
+        let message = format!("Write {}", name.name().as_str());
+        let target = name.clone();
+
+        self.commit(target.name(), message, move |repo, base, builder| {
+            let name = name.name();
+            if let Ok(entry) = base.get_path(Path::new(name.as_str())) {
+                if entry.kind() == Some(ObjectType::Tree) {
+                    let msg = format!("Is a directory: '{}'", name.as_str());
+                    return Err(IdxError::storage_error_msg(msg));
+                }
+            }
+
+            let id = repo.blob(&data)
+                .map_err(IdxError::storage_error)?;
+            builder.upsert(name.as_str(), id, FileMode::Blob);
+            Ok(())
+        }).await
+    }
+
+    /// Add `to` as a copy of `from`, optionally removing `from`
+    async fn copy_entry(&self, from: ObjectName<'_>, to: ObjectName<'_>, remove: bool) -> IdxResult<()> {
+        let message = if remove {
+            format!("Rename {} to {}", from.as_str(), to.as_str())
+        } else {
+            format!("Copy {} to {}", from.as_str(), to.as_str())
+        };
+        let (from_buf, to_buf) = (ObjectNameBuf::from(from), ObjectNameBuf::from(to));
+
+        self.commit(to, message, move |_, base, builder| {
+            let (from, to) = (from_buf.name(), to_buf.name());
+            let entry = base.get_path(Path::new(from.as_str()))
+                .map_err(|e| git_error(e, from))?;
+            if base.get_path(Path::new(to.as_str())).is_ok() {
+                return Err(already_exists(to));
+            }
+            if entry.kind() != Some(ObjectType::Blob) {
+                let msg = format!("Can't move or copy directory: '{}'", from.as_str());
+                return Err(IdxError::storage_error_msg(msg));
+            }
+
+            let mode = if entry.filemode() == LINK_MODE {
+                FileMode::Link
+            } else {
+                FileMode::Blob
+            };
+            if remove {
+                builder.remove(from.as_str());
+            }
+            builder.upsert(to.as_str(), entry.id(), mode);
+            Ok(())
+        }).await
+    }
+}
+
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+fn already_exists(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("Object exists: '{}'", name.as_str())).into()
+}
+
+fn not_a_directory(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotADirectory, format!("Not a directory: '{}'", name.as_str())).into()
+}
+
+fn git_error(e: git2::Error, name: ObjectName<'_>) -> IdxError {
+    if e.code() == ErrorCode::NotFound {
+        not_found(name)
+    } else {
+        IdxError::storage_error(e)
+    }
+}
+
+
+#[async_trait]
+impl AccessStorage for GitStorage {
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let dir_name = ObjectNameBuf::from(dir_name);
+
+        self.with_repo(move |storage, repo| {
+            let dir_name = dir_name.name();
+            let tree = match storage.dir(repo, dir_name)? {
+                Some(tree) => tree,
+                None => return Ok(vec![]),
+            };
+
+            let mut rv = Vec::with_capacity(tree.len());
+            for entry in tree.iter() {
+                // git allows names objects can't have, like those with a backslash
+                let file_name = match entry.name() {
+                    Some(file_name) if ObjectName::new(file_name).is_ok() => file_name,
+                    _ => continue,
+                };
+                let name = if dir_name.as_str().is_empty() {
+                    ObjectNameBuf::from_str(file_name)?
+                } else {
+                    ObjectNameBuf::from_path(format!("{}/{}", dir_name.as_str(), file_name))?
+                };
+
+                let kind = match entry.kind() {
+                    Some(ObjectType::Tree) => EntryKind::Dir,
+                    Some(ObjectType::Blob) if entry.filemode() == LINK_MODE => EntryKind::Symlink,
+                    Some(ObjectType::Blob) => EntryKind::File,
+                    _ => EntryKind::Other,
+                };
+                rv.push(DirEntry::new(name, kind));
+            }
+
+            Ok(rv)
+        }).await
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let obj_name = ObjectNameBuf::from(obj_name);
+
+        self.with_repo(move |storage, repo| storage.blob_data(repo, obj_name.name())).await
+    }
+
+
+    /// Open a writer that commits the object once it is shut down
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        if let Revision::Commit(_) = self.revision {
+            return Err(IdxError::ReadOnly(obj_name.as_str().to_string()));
+        }
+
+        let storage = self.clone();
+        let name = ObjectNameBuf::from(obj_name);
+
+        Ok(Box::new(CommitWriter::new(move |data| async move {
+            storage.write_blob(name, data).await
+        })))
+    }
+
+
+    /// Query size and digest of an object
+    ///
+    /// Git doesn't record modification times of single objects.
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let obj_name = ObjectNameBuf::from(obj_name);
+
+        self.with_repo(move |storage, repo| {
+            let obj_name = obj_name.name();
+            if with_digest {
+                let data = storage.blob_data(repo, obj_name)?;
+                return Ok(ObjectMetadata::new(data.len() as u64, None, Some(ContentDigest::of(&data))));
+            }
+
+            let entry = storage.entry(repo, obj_name)?;
+            let blob = repo.find_blob(entry.id())
+                .map_err(|_| IdxError::storage_error_msg(format!("Is a directory: '{}'", obj_name.as_str())))?;
+            Ok(ObjectMetadata::new(blob.size() as u64, None, None))
+        }).await
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        self.write_blob(name.into(), data.as_ref().to_vec()).await
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        if obj_name.as_str().is_empty() {
+            return Ok(true);
+        }
+
+        let obj_name = ObjectNameBuf::from(obj_name);
+
+        self.with_repo(move |storage, repo| {
+            match storage.entry(repo, obj_name.name()) {
+                Ok(_) => Ok(true),
+                Err(e) if e.is_not_found() => Ok(false),
+                Err(e) => Err(e),
+            }
+        }).await
+    }
+
+
+    /// Delete an object, directories always hold objects and can't be deleted
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let message = format!("Delete {}", obj_name.as_str());
+
+        let name = ObjectNameBuf::from(obj_name);
+
+        self.commit(obj_name, message, move |_, base, builder| {
+            let obj_name = name.name();
+            let entry = base.get_path(Path::new(obj_name.as_str()))
+                .map_err(|e| git_error(e, obj_name))?;
+            if entry.kind() == Some(ObjectType::Tree) {
+                let msg = format!("Directory not empty: '{}'", obj_name.as_str());
+                return Err(IdxError::storage_error_msg(msg));
+            }
+
+            builder.remove(obj_name.as_str());
+            Ok(())
+        }).await
+    }
+
+
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        self.copy_entry(from, to, true).await
+    }
+
+
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        self.copy_entry(from, to, false).await
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        if let Revision::Commit(_) = self.revision {
+            return Err(IdxError::ReadOnly(dir_name.as_str().to_string()));
+        }
+
+        let msg = format!("Git trees can't hold empty directories: '{}'", dir_name.as_str());
+        Err(IdxError::storage_error_msg(msg))
+    }
+}
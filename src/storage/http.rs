@@ -0,0 +1,471 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc,Mutex};
+use async_trait::async_trait;
+use hyper::{Body,Client,HeaderMap,Method,Request,StatusCode,Uri};
+use hyper::body::Bytes;
+use hyper::client::HttpConnector;
+use hyper_rustls::HttpsConnector;
+use percent_encoding::{AsciiSet,NON_ALPHANUMERIC,percent_decode_str,utf8_percent_encode};
+use serde::Deserialize;
+
+/// Characters left alone when putting object names into URLs
+const PATH_CHARS: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b'/');
+
+/// Number of redirects followed for a single request
+const MAX_REDIRECTS: usize = 10;
+
+/// Read-only storage for the files published by a static HTTP server
+///
+/// Objects are fetched with GET requests relative to the base URL. Listings
+/// come either from the directory index pages generated by the server, or
+/// from a manifest file listing all objects. Index pages can be HTML pages
+/// with a link for each entry, as produced by Apache and nginx, or JSON in
+/// the format of nginx's `autoindex_format json`. Links to directories end
+/// with a slash. Entries whose names aren't valid object names are left out.
+///
+/// All changes fail with `IdxError::ReadOnly`.
+#[derive(Clone)]
+pub struct HttpStorage {
+    client: Client<HttpsConnector<HttpConnector>>,
+    base_url: String,
+    manifest: Option<Manifest>,
+}
+
+/// Manifest file together with its contents once they were fetched
+#[derive(Clone)]
+struct Manifest {
+    name: String,
+    paths: Arc<Mutex<Option<Arc<Vec<String>>>>>,
+}
+
+/// Status, headers and body of a completed request
+struct HttpResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// Entry of a JSON directory index
+#[derive(Deserialize)]
+struct IndexEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+
+impl HttpStorage {
+    /// Access the files below `base_url`, listing directories by their index pages
+    pub fn new(base_url: &str) -> IdxResult<Self> {
+        let base_url = base_url.trim_end_matches('/');
+        let uri: Uri = base_url.parse()
+            .map_err(IdxError::storage_error)?;
+        if !matches!(uri.scheme_str(), Some("http") | Some("https")) || uri.authority().is_none() {
+            return Err(IdxError::storage_error_msg(format!("Not an HTTP(S) URL: '{}'", base_url)));
+        }
+
+        Ok(Self {
+            client: Client::builder().build(HttpsConnector::new()),
+            base_url: base_url.to_string(),
+            manifest: None,
+        })
+    }
+
+    /// List objects from a manifest instead of directory index pages
+    ///
+    /// The manifest is a JSON array with the names of all objects, like
+    /// `["a.json", "sub/b.json"]`. Directories are implied by the names. It
+    /// is fetched once, with the first listing, and kept for the lifetime of
+    /// the storage and its clones, so later changes to the manifest are only
+    /// seen by a newly created storage.
+    pub fn manifest(mut self, manifest_name: impl Into<String>) -> Self {
+        self.manifest = Some(Manifest {
+            name: manifest_name.into(),
+            paths: Arc::new(Mutex::new(None)),
+        });
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, utf8_percent_encode(path, PATH_CHARS))
+    }
+
+    async fn send(&self, method: Method, url: &str) -> IdxResult<HttpResponse> {
+        let req = Request::builder()
+            .method(method)
+            .uri(url)
+            .body(Body::empty())
+            .map_err(IdxError::storage_error)?;
+        let resp = self.client.request(req).await
+            .map_err(IdxError::storage_error)?;
+
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = hyper::body::to_bytes(resp.into_body()).await
+            .map_err(IdxError::storage_error)?;
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    /// Fetch a file, `None` if the server doesn't have it
+    ///
+    /// Redirects are followed, except those to the same URL with a slash
+    /// appended, which is how servers send requests for directories to the
+    /// index page. These count as missing files.
+    async fn fetch(&self, method: Method, path: &str) -> IdxResult<Option<HttpResponse>> {
+        let mut url = self.url(path);
+
+        for _ in 0..=MAX_REDIRECTS {
+            let resp = self.send(method.clone(), &url).await?;
+            if !resp.status.is_redirection() {
+                return found_response(path, resp);
+            }
+
+            let location = resp.headers.get("location")
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| response_error(path, &resp))?;
+            let target = redirect_target(&url, location)?;
+            if target == format!("{}/", url) {
+                return Ok(None);
+            }
+            url = target;
+        }
+
+        Err(IdxError::storage_error_msg(format!("Too many redirects for '{}'", path)))
+    }
+
+    /// Fetch the index page of a directory, `None` if there is none
+    ///
+    /// Redirects count as missing directories, as they lead away from the
+    /// directory's own index page.
+    async fn fetch_index(&self, method: Method, path: &str) -> IdxResult<Option<HttpResponse>> {
+        let resp = self.send(method, &self.url(path)).await?;
+
+        if resp.status.is_redirection() {
+            return Ok(None);
+        }
+        found_response(path, resp)
+    }
+
+    async fn manifest_paths(&self, manifest: &Manifest) -> IdxResult<Arc<Vec<String>>> {
+        if let Some(paths) = &*lock(&manifest.paths)? {
+            return Ok(paths.clone());
+        }
+
+        let resp = self.fetch(Method::GET, &manifest.name).await?;
+        let resp = resp.ok_or_else(|| IdxError::storage_error_msg(format!("Missing manifest: '{}'", manifest.name)))?;
+        let paths: Vec<String> = serde_json::from_slice(&resp.body)?;
+        let paths = Arc::new(paths);
+
+        *lock(&manifest.paths)? = Some(paths.clone());
+        Ok(paths)
+    }
+
+    /// Entries of a directory in the manifest, `None` if it's not a directory
+    async fn manifest_entries(&self, manifest: &Manifest, dir_name: ObjectName<'_>) -> IdxResult<Option<Vec<(String,EntryKind)>>> {
+        let paths = self.manifest_paths(manifest).await?;
+        let prefix = if dir_name.as_str().is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir_name.as_str())
+        };
+
+        let mut found = false;
+        let mut rv = BTreeMap::new();
+        for rest in paths.iter().filter_map(|path| path.strip_prefix(&prefix)) {
+            found = true;
+            match rest.find('/') {
+                Some(pos) => rv.insert(rest[..pos].to_string(), EntryKind::Dir),
+                None => rv.insert(rest.to_string(), EntryKind::File),
+            };
+        }
+
+        if !found && !prefix.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(rv.into_iter().collect()))
+    }
+
+    /// Entries of a directory from its index page, `None` if it's not a directory
+    async fn index_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Option<Vec<(String,EntryKind)>>> {
+        let path = if dir_name.as_str().is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir_name.as_str())
+        };
+        let resp = match self.fetch_index(Method::GET, &path).await? {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+
+        let is_json = resp.headers.get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("json"));
+        let links = if is_json {
+            let entries: Vec<IndexEntry> = serde_json::from_slice(&resp.body)?;
+            entries.into_iter()
+                .map(|entry| {
+                    let kind = if entry.kind == "directory" { EntryKind::Dir } else { EntryKind::File };
+                    (entry.name, kind)
+                })
+                .collect()
+        } else {
+            parse_index_page(&String::from_utf8_lossy(&resp.body))
+        };
+
+        Ok(Some(links))
+    }
+
+    async fn is_dir(&self, name: ObjectName<'_>) -> IdxResult<bool> {
+        match &self.manifest {
+            Some(manifest) => Ok(self.manifest_entries(manifest, name).await?.is_some()),
+            None => Ok(self.fetch_index(Method::HEAD, &format!("{}/", name.as_str())).await?.is_some()),
+        }
+    }
+}
+
+
+fn lock<T>(mutex: &Mutex<T>) -> IdxResult<std::sync::MutexGuard<'_, T>> {
+    mutex.lock()
+        .map_err(|_| IdxError::storage_error_msg("HTTP storage manifest lock poisoned"))
+}
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+fn response_error(path: &str, resp: &HttpResponse) -> IdxError {
+    IdxError::storage_error_msg(format!("HTTP request for '{}' failed with {}", path, resp.status))
+}
+
+/// Pass on successful responses, turn a 404 into `None` and anything else
+/// into an error
+fn found_response(path: &str, resp: HttpResponse) -> IdxResult<Option<HttpResponse>> {
+    if resp.status.is_success() {
+        Ok(Some(resp))
+    } else if resp.status == StatusCode::NOT_FOUND {
+        Ok(None)
+    } else {
+        Err(response_error(path, &resp))
+    }
+}
+
+/// Resolve the location of a redirect against the URL that was requested
+fn redirect_target(url: &str, location: &str) -> IdxResult<String> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return Ok(location.to_string());
+    }
+
+    let uri: Uri = url.parse()
+        .map_err(IdxError::storage_error)?;
+    let scheme = uri.scheme_str().unwrap_or("http");
+    let authority = uri.authority().map(|a| a.as_str()).unwrap_or_default();
+
+    if location.starts_with("//") {
+        Ok(format!("{}:{}", scheme, location))
+    } else if location.starts_with('/') {
+        Ok(format!("{}://{}{}", scheme, authority, location))
+    } else {
+        let path = uri.path();
+        let dir = &path[..path.rfind('/').map_or(0, |pos| pos + 1)];
+        Ok(format!("{}://{}{}{}", scheme, authority, dir, location))
+    }
+}
+
+/// Collect the entries linked from an HTML index page
+///
+/// Only relative links to entries of the directory itself are kept, which
+/// skips links to parent directories and the sorting links in the header.
+fn parse_index_page(page: &str) -> Vec<(String,EntryKind)> {
+    let mut rv = BTreeMap::new();
+    let mut rest = page;
+
+    while let Some(pos) = rest.find("href=") {
+        rest = &rest[pos + 5..];
+        let quote = match rest.chars().next() {
+            Some(quote @ '"') | Some(quote @ '\'') => quote,
+            _ => continue,
+        };
+        let end = match rest[1..].find(quote) {
+            Some(end) => end + 1,
+            None => break,
+        };
+        let href = rest[1..end].replace("&amp;", "&");
+        rest = &rest[end..];
+
+        if href.starts_with(['/', '?', '#']) || href.starts_with("./") || href.starts_with("../") || href.contains(':') {
+            continue;
+        }
+        let (name, kind) = match href.strip_suffix('/') {
+            Some(name) => (name, EntryKind::Dir),
+            None => (href.as_str(), EntryKind::File),
+        };
+        let name = percent_decode_str(name).decode_utf8_lossy();
+        if !name.is_empty() && !name.contains('/') {
+            rv.insert(name.into_owned(), kind);
+        }
+    }
+
+    rv.into_iter().collect()
+}
+
+
+#[async_trait]
+impl AccessStorage for HttpStorage {
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let entries = match &self.manifest {
+            Some(manifest) => self.manifest_entries(manifest, dir_name).await?,
+            None => self.index_entries(dir_name).await?,
+        };
+        let entries = match entries {
+            Some(entries) => entries,
+            None => return Err(not_found(dir_name)),
+        };
+
+        Ok(entries.into_iter()
+            .filter_map(|(file_name, kind)| {
+                let name = if dir_name.as_str().is_empty() {
+                    ObjectNameBuf::from_str(file_name)
+                } else {
+                    ObjectNameBuf::from_path(format!("{}/{}", dir_name.as_str(), file_name))
+                };
+                name.ok().map(|name| DirEntry::new(name, kind))
+            })
+            .collect())
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        match self.fetch(Method::GET, obj_name.as_str()).await? {
+            Some(resp) => Ok(resp.body.to_vec()),
+            None => Err(not_found(obj_name)),
+        }
+    }
+
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        Err(IdxError::ReadOnly(obj_name.as_str().to_string()))
+    }
+
+
+    /// Query size and modification time from the headers of a HEAD request
+    ///
+    /// Directories have a size of zero and no modification time.
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let resp = self.fetch(Method::HEAD, obj_name.as_str()).await?;
+        let resp = match resp {
+            Some(resp) if !obj_name.as_str().is_empty() => resp,
+            _ if self.is_dir(obj_name).await? => return Ok(ObjectMetadata::new(0, None, None)),
+            _ => return Err(not_found(obj_name)),
+        };
+
+        let header = |name| resp.headers.get(name)
+            .and_then(|value: &hyper::header::HeaderValue| value.to_str().ok());
+        let modified = header("last-modified")
+            .and_then(|s| httpdate::parse_http_date(s).ok());
+        let size = header("content-length")
+            .and_then(|s| s.parse().ok());
+
+        match (size, with_digest) {
+            (Some(size), false) => Ok(ObjectMetadata::new(size, modified, None)),
+            _ => {
+                let byte_data = self.read_bytes(obj_name).await?;
+                let digest = if with_digest {
+                    Some(ContentDigest::of(&byte_data))
+                } else {
+                    None
+                };
+                Ok(ObjectMetadata::new(byte_data.len() as u64, modified, digest))
+            }
+        }
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, _data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        Err(IdxError::ReadOnly(name.as_str().to_string()))
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        if obj_name.as_str().is_empty() {
+            return Ok(true);
+        }
+
+        Ok(self.fetch(Method::HEAD, obj_name.as_str()).await?.is_some() || self.is_dir(obj_name).await?)
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(IdxError::ReadOnly(obj_name.as_str().to_string()))
+    }
+
+
+    async fn rename(&self, from: ObjectName<'_>, _to: ObjectName<'_>) -> IdxResult<()> {
+        Err(IdxError::ReadOnly(from.as_str().to_string()))
+    }
+
+
+    async fn copy(&self, _from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        Err(IdxError::ReadOnly(to.as_str().to_string()))
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        Err(IdxError::ReadOnly(dir_name.as_str().to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_index_page() {
+        let page = r#"<html><head><title>Index of /data/</title></head><body>
+            <h1>Index of /data/</h1><hr><pre>
+            <a href="?C=N;O=D">Name</a> <a href='?C=M;O=A'>Last modified</a>
+            <a href="../">../</a>
+            <a href="/icons/">icons</a>
+            <a href="http://example.com/">elsewhere</a>
+            <a href="sub/">sub/</a>
+            <a href="a%20b.json">a b.json</a>
+            <a href="x&amp;y.json">x&amp;y.json</a>
+            <a href="sub/">sub/</a>
+            </pre><hr></body></html>"#;
+
+        let entries = parse_index_page(page);
+        assert_eq!(vec![
+            ("a b.json".to_string(), EntryKind::File),
+            ("sub".to_string(), EntryKind::Dir),
+            ("x&y.json".to_string(), EntryKind::File),
+        ], entries);
+    }
+}
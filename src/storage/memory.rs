@@ -0,0 +1,372 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::collections::{BTreeMap,BTreeSet};
+use std::io;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::{Arc,RwLock,RwLockReadGuard,RwLockWriteGuard};
+use std::task::{Context,Poll};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use tokio::io::AsyncWrite;
+
+/// Storage keeping all objects in memory
+///
+/// Clones share the same underlying objects, so a `MemoryStorage` can be
+/// handed to the indexers like a `FileStorage`. Directories are implicit: an
+/// object named `dir/foo` makes `dir` show up in the listing of its parent.
+/// Empty directories only exist after `create_dir`.
+#[derive(Clone,Default)]
+pub struct MemoryStorage {
+    tree: Arc<RwLock<MemoryTree>>,
+}
+
+#[derive(Default)]
+struct MemoryTree {
+    objects: BTreeMap<String,MemoryObject>,
+    dirs: BTreeSet<String>,
+}
+
+#[derive(Clone)]
+struct MemoryObject {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn dir_prefix(dir_name: ObjectName<'_>) -> String {
+        if dir_name.as_str().is_empty() {
+            String::new()
+        } else {
+            format!("{}/", dir_name.as_str())
+        }
+    }
+
+    fn read_tree(&self) -> IdxResult<RwLockReadGuard<'_, MemoryTree>> {
+        self.tree.read()
+            .map_err(|_| IdxError::storage_error_msg("Memory storage lock poisoned"))
+    }
+
+    fn write_tree(&self) -> IdxResult<RwLockWriteGuard<'_, MemoryTree>> {
+        self.tree.write()
+            .map_err(|_| IdxError::storage_error_msg("Memory storage lock poisoned"))
+    }
+}
+
+
+impl MemoryTree {
+    /// Check for an explicitly created directory or any object below `name`
+    fn is_dir(&self, name: ObjectName<'_>) -> bool {
+        name.as_str().is_empty()
+            || self.dirs.contains(name.as_str())
+            || self.has_children(name)
+    }
+
+    /// Check for objects or directories below `name`, keys below it follow
+    /// the prefix directly in the sorted maps
+    fn has_children(&self, name: ObjectName<'_>) -> bool {
+        let prefix = MemoryStorage::dir_prefix(name);
+
+        self.dirs.range::<str, _>(starting_at(&prefix)).next()
+            .is_some_and(|d| d.starts_with(&prefix))
+            || self.objects.range::<str, _>(starting_at(&prefix)).next()
+                .is_some_and(|(k, _)| k.starts_with(&prefix))
+    }
+
+    /// Fail if an object is in the place of one of the parent directories
+    fn check_parent(&self, name: ObjectName<'_>) -> IdxResult<()> {
+        let name = name.as_str();
+
+        for (pos, _) in name.match_indices('/') {
+            if self.objects.contains_key(&name[..pos]) {
+                return Err(not_a_directory(ObjectName::from_path(&name[..pos])?));
+            }
+        }
+        Ok(())
+    }
+
+    /// Move everything below the directory `from` below `to`
+    fn move_children(&mut self, from: ObjectName<'_>, to: ObjectName<'_>) {
+        let (from, to) = (MemoryStorage::dir_prefix(from), MemoryStorage::dir_prefix(to));
+
+        let dirs: Vec<String> = self.dirs.range::<str, _>(starting_at(&from))
+            .take_while(|d| d.starts_with(&from))
+            .cloned()
+            .collect();
+        for dir in dirs {
+            self.dirs.remove(&dir);
+            self.dirs.insert(format!("{}{}", to, &dir[from.len()..]));
+        }
+
+        let keys: Vec<String> = self.objects.range::<str, _>(starting_at(&from))
+            .map(|(k, _)| k)
+            .take_while(|k| k.starts_with(&from))
+            .cloned()
+            .collect();
+        for key in keys {
+            if let Some(obj) = self.objects.remove(&key) {
+                self.objects.insert(format!("{}{}", to, &key[from.len()..]), obj);
+            }
+        }
+    }
+
+    fn exists(&self, name: ObjectName<'_>) -> bool {
+        self.objects.contains_key(name.as_str()) || self.is_dir(name)
+    }
+}
+
+
+/// Range of the keys sorting at or after `key`
+fn starting_at(key: &str) -> (Bound<&str>, Bound<&str>) {
+    (Bound::Included(key), Bound::Unbounded)
+}
+
+
+/// Writer collecting data in a buffer until it is stored on shutdown
+struct MemoryWriter {
+    tree: Arc<RwLock<MemoryTree>>,
+    name: String,
+    buf: Vec<u8>,
+}
+
+impl AsyncWrite for MemoryWriter {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.get_mut().buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut tree = this.tree.write()
+            .map_err(|_| io::Error::other("Memory storage lock poisoned"))?;
+
+        let obj = MemoryObject {
+            data: std::mem::take(&mut this.buf),
+            modified: SystemTime::now(),
+        };
+        tree.objects.insert(this.name.clone(), obj);
+        Poll::Ready(Ok(()))
+    }
+}
+
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+fn already_exists(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("Object exists: '{}'", name.as_str())).into()
+}
+
+fn not_a_directory(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotADirectory, format!("Not a directory: '{}'", name.as_str())).into()
+}
+
+
+#[async_trait]
+impl AccessStorage for MemoryStorage {
+    type ListIntoIter = Vec<String>;
+
+    /// List the direct children of a directory, but not recursively
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter>
+    {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let tree = self.read_tree()?;
+        if !tree.is_dir(dir_name) {
+            if tree.objects.contains_key(dir_name.as_str()) {
+                return Err(not_a_directory(dir_name));
+            }
+            return Err(not_found(dir_name));
+        }
+
+        // a child shows up once for every object below it, so collect them in a map
+        let prefix = Self::dir_prefix(dir_name);
+        let mut children = BTreeMap::new();
+        let dir_keys = tree.dirs.iter().map(|d| (d, true));
+        let object_keys = tree.objects.keys().map(|k| (k, false));
+
+        for (key, key_is_dir) in dir_keys.chain(object_keys).filter(|(k, _)| k.starts_with(&prefix)) {
+            let rest = &key[prefix.len()..];
+            let (child, kind) = match rest.find('/') {
+                Some(pos) => (&rest[..pos], EntryKind::Dir),
+                None if key_is_dir => (rest, EntryKind::Dir),
+                None => (rest, EntryKind::File),
+            };
+            children.insert(format!("{}{}", prefix, child), kind);
+        }
+
+        children.into_iter()
+            .map(|(name, kind)| Ok(DirEntry::new(ObjectNameBuf::from_path(name)?, kind)))
+            .collect()
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let tree = self.read_tree()?;
+
+        tree.objects.get(obj_name.as_str())
+            .map(|obj| obj.data.clone())
+            .ok_or_else(|| not_found(obj_name))
+    }
+
+
+    /// Open a writer that stores the object once it is shut down
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let tree = self.read_tree()?;
+        if tree.is_dir(obj_name) {
+            let msg = format!("Is a directory: '{}'", obj_name.as_str());
+            return Err(IdxError::storage_error_msg(msg));
+        }
+        tree.check_parent(obj_name)?;
+
+        Ok(Box::new(MemoryWriter {
+            tree: self.tree.clone(),
+            name: obj_name.as_str().to_string(),
+            buf: vec![],
+        }))
+    }
+
+
+    /// Query metadata, directories have a size of zero and no modification time
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let tree = self.read_tree()?;
+
+        let obj = match tree.objects.get(obj_name.as_str()) {
+            Some(obj) => obj,
+            None if tree.is_dir(obj_name) => return Ok(ObjectMetadata::new(0, None, None)),
+            None => return Err(not_found(obj_name)),
+        };
+        let digest = if with_digest {
+            Some(ContentDigest::of(&obj.data))
+        } else {
+            None
+        };
+
+        Ok(ObjectMetadata::new(obj.data.len() as u64, Some(obj.modified), digest))
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        let mut tree = self.write_tree()?;
+
+        if tree.is_dir(name) {
+            let msg = format!("Is a directory: '{}'", name.as_str());
+            return Err(IdxError::storage_error_msg(msg));
+        }
+        tree.check_parent(name)?;
+
+        let obj = MemoryObject {
+            data: data.as_ref().to_vec(),
+            modified: SystemTime::now(),
+        };
+        tree.objects.insert(name.as_str().to_string(), obj);
+        Ok(())
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        Ok(self.read_tree()?.exists(obj_name))
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let mut tree = self.write_tree()?;
+
+        if tree.objects.remove(obj_name.as_str()).is_some() {
+            return Ok(());
+        }
+
+        if !tree.is_dir(obj_name) {
+            return Err(not_found(obj_name));
+        }
+
+        if tree.has_children(obj_name) {
+            let msg = format!("Directory not empty: '{}'", obj_name.as_str());
+            return Err(IdxError::storage_error_msg(msg));
+        }
+
+        tree.dirs.remove(obj_name.as_str());
+        Ok(())
+    }
+
+
+    /// Rename an object, directories are moved with everything below them
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let mut tree = self.write_tree()?;
+
+        if !tree.exists(from) {
+            return Err(not_found(from));
+        }
+        if tree.exists(to) {
+            return Err(already_exists(to));
+        }
+        tree.check_parent(to)?;
+
+        if let Some(obj) = tree.objects.remove(from.as_str()) {
+            tree.objects.insert(to.as_str().to_string(), obj);
+            return Ok(());
+        }
+
+        if from.as_str().is_empty() || to.as_str().starts_with(&Self::dir_prefix(from)) {
+            let msg = format!("Can't move directory '{}' into itself", from.as_str());
+            return Err(IdxError::storage_error_msg(msg));
+        }
+
+        tree.move_children(from, to);
+        if tree.dirs.remove(from.as_str()) {
+            tree.dirs.insert(to.as_str().to_string());
+        }
+        Ok(())
+    }
+
+
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let mut tree = self.write_tree()?;
+
+        let mut obj = tree.objects.get(from.as_str())
+            .cloned()
+            .ok_or_else(|| not_found(from))?;
+        if tree.exists(to) {
+            return Err(already_exists(to));
+        }
+        tree.check_parent(to)?;
+        obj.modified = SystemTime::now();
+        tree.objects.insert(to.as_str().to_string(), obj);
+        Ok(())
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        let mut tree = self.write_tree()?;
+
+        if tree.exists(dir_name) {
+            return Err(already_exists(dir_name));
+        }
+        tree.check_parent(dir_name)?;
+
+        tree.dirs.insert(dir_name.as_str().to_string());
+        Ok(())
+    }
+}
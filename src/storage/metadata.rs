@@ -0,0 +1,107 @@
+use serde::{Serialize,Deserialize};
+use sha2::{Digest,Sha256};
+use std::fmt;
+use std::time::SystemTime;
+
+/// SHA-256 digest of the contents of an object
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash,Serialize,Deserialize)]
+pub struct ContentDigest([u8; 32]);
+
+impl ContentDigest {
+    /// Compute the digest of a complete object
+    pub fn of(data: &[u8]) -> Self {
+        let mut hasher = DigestBuilder::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Parse a digest from the 64 hex digits of its `Display` form
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != 64 || !hex.is_ascii() {
+            return None;
+        }
+
+        let mut rv = [0u8; 32];
+        for (i, b) in rv.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(ContentDigest(rv))
+    }
+}
+
+impl fmt::Display for ContentDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Incrementally compute a `ContentDigest` over data arriving in chunks
+pub struct DigestBuilder {
+    hasher: Sha256,
+}
+
+impl DigestBuilder {
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha256::new()
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> ContentDigest {
+        let mut rv = [0u8; 32];
+        rv.copy_from_slice(&self.hasher.finalize());
+        ContentDigest(rv)
+    }
+}
+
+impl Default for DigestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+/// Information about an object returned by `AccessStorage::stat`
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct ObjectMetadata {
+    size: u64,
+    modified: Option<SystemTime>,
+    digest: Option<ContentDigest>,
+}
+
+impl ObjectMetadata {
+    pub fn new(size: u64, modified: Option<SystemTime>, digest: Option<ContentDigest>) -> Self {
+        Self {
+            size,
+            modified,
+            digest
+        }
+    }
+
+    /// Size of the object in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Time of the last modification, if the storage keeps track of it
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Digest of the contents, only present if it was requested
+    pub fn digest(&self) -> Option<&ContentDigest> {
+        self.digest.as_ref()
+    }
+}
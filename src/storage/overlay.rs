@@ -0,0 +1,350 @@
+use super::{AccessStorage,ObjectName,ObjectReader,ObjectWriter,DirEntry,ObjectMetadata};
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::collections::{BTreeMap,BTreeSet};
+use std::io;
+use async_trait::async_trait;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+/// Storage layering a writable upper storage over a lower one
+///
+/// Reads look at the upper layer first and fall back to the lower layer,
+/// listings merge the entries of both. All changes go to the upper layer, the
+/// lower layer is never modified. More layers are stacked by using another
+/// `OverlayStorage` as the lower layer.
+///
+/// Objects only present in the lower layer can't be deleted, unless
+/// whiteouts are enabled. A deletion then leaves an empty marker object named
+/// `.wh.<name>` in the upper layer, which hides the object, or everything
+/// below a directory, in the lower layer. Names starting with `.wh.` are
+/// reserved for these markers, they are never listed and can't be written.
+///
+/// The default `walk` is used, so symlinks are never descended into.
+#[derive(Clone)]
+pub struct OverlayStorage<U,L> {
+    upper: U,
+    lower: L,
+    whiteouts: bool,
+}
+
+
+impl<U,L> OverlayStorage<U,L> {
+    pub fn new(upper: U, lower: L) -> Self {
+        Self {
+            upper,
+            lower,
+            whiteouts: false,
+        }
+    }
+
+    /// Select whether deleting objects of the lower layer leaves whiteouts
+    pub fn whiteouts(mut self, enable: bool) -> Self {
+        self.whiteouts = enable;
+        self
+    }
+
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    pub fn lower(&self) -> &L {
+        &self.lower
+    }
+}
+
+
+/// Name of the marker hiding `name` in the lower layer
+fn whiteout_name(name: &str) -> IdxResult<ObjectNameBuf> {
+    let marker = match name.rfind('/') {
+        Some(pos) => format!("{}/{}{}", &name[..pos], WHITEOUT_PREFIX, &name[pos + 1..]),
+        None => format!("{}{}", WHITEOUT_PREFIX, name),
+    };
+
+    ObjectNameBuf::from_path(marker)
+}
+
+/// Name hidden by a whiteout entry, or `None` for regular entries
+fn hidden_name(entry: &DirEntry) -> Option<String> {
+    let name = entry.name();
+    let name = name.as_str();
+    let (dir, base) = match name.rfind('/') {
+        Some(pos) => name.split_at(pos + 1),
+        None => ("", name),
+    };
+
+    base.strip_prefix(WHITEOUT_PREFIX)
+        .map(|hidden| format!("{}{}", dir, hidden))
+}
+
+/// Refuse names whose last segment is reserved for whiteouts
+fn check_name(name: ObjectName<'_>) -> IdxResult<()> {
+    let base = name.as_str().rsplit('/').next().unwrap_or_default();
+
+    if base.starts_with(WHITEOUT_PREFIX) {
+        let msg = format!("Name is reserved for whiteouts: '{}'", name.as_str());
+        return Err(IdxError::storage_error_msg(msg));
+    }
+    Ok(())
+}
+
+fn not_found(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name.as_str())).into()
+}
+
+fn already_exists(name: ObjectName<'_>) -> IdxError {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("Object exists: '{}'", name.as_str())).into()
+}
+
+/// Turn not-found errors into `None`, so missing objects can fall through to
+/// the next layer
+fn found<T>(res: IdxResult<T>) -> IdxResult<Option<T>> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(e) if e.is_not_found() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
+impl<U,L> OverlayStorage<U,L>
+    where
+        U: AccessStorage + Send + Sync,
+        L: AccessStorage + Send + Sync
+{
+    /// Check whether the object or one of its parent directories was deleted
+    /// from the lower layer
+    async fn whited_out(&self, name: ObjectName<'_>) -> IdxResult<bool> {
+        if !self.whiteouts || name.as_str().is_empty() {
+            return Ok(false);
+        }
+
+        let name = name.as_str();
+        let ends = name.match_indices('/').map(|(pos, _)| pos).chain(std::iter::once(name.len()));
+        for end in ends {
+            let marker = whiteout_name(&name[..end])?;
+            if self.upper.exists(marker.name()).await? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    async fn in_lower(&self, name: ObjectName<'_>) -> IdxResult<bool> {
+        Ok(!self.whited_out(name).await? && self.lower.exists(name).await?)
+    }
+
+    async fn hide(&self, name: ObjectName<'_>) -> IdxResult<()> {
+        let marker = whiteout_name(name.as_str())?;
+
+        self.upper.write_bytes(marker.name(), []).await
+    }
+}
+
+
+#[async_trait]
+impl<U,L> AccessStorage for OverlayStorage<U,L>
+    where
+        U: AccessStorage + Send + Sync,
+        L: AccessStorage + Send + Sync
+{
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    /// Merge the entries of both layers, the upper layer wins if both have an
+    /// entry of the same name
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let upper = found(self.upper.list_entries(dir_name).await)?;
+        let lower = if self.whited_out(dir_name).await? {
+            None
+        } else {
+            found(self.lower.list_entries(dir_name).await)?
+        };
+
+        if upper.is_none() && lower.is_none() {
+            return Err(not_found(dir_name));
+        }
+
+        let mut merged = BTreeMap::new();
+        let mut hidden = BTreeSet::new();
+        for entry in upper.unwrap_or_default() {
+            match hidden_name(&entry) {
+                Some(name) => { hidden.insert(name); }
+                None => { merged.insert(entry.name().as_str().to_string(), entry); }
+            }
+        }
+
+        for entry in lower.unwrap_or_default() {
+            let name = entry.name().as_str().to_string();
+            if !hidden.contains(&name) && !merged.contains_key(&name) {
+                merged.insert(name, entry);
+            }
+        }
+
+        Ok(merged.into_values().collect())
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        if let Some(data) = found(self.upper.read_bytes(obj_name).await)? {
+            return Ok(data);
+        }
+        if self.whited_out(obj_name).await? {
+            return Err(not_found(obj_name));
+        }
+
+        self.lower.read_bytes(obj_name).await
+    }
+
+
+    async fn open_read(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectReader> {
+        if let Some(reader) = found(self.upper.open_read(obj_name).await)? {
+            return Ok(reader);
+        }
+        if self.whited_out(obj_name).await? {
+            return Err(not_found(obj_name));
+        }
+
+        self.lower.open_read(obj_name).await
+    }
+
+
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        check_name(obj_name)?;
+        self.upper.open_write(obj_name).await
+    }
+
+
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        if let Some(metadata) = found(self.upper.stat(obj_name, with_digest).await)? {
+            return Ok(metadata);
+        }
+        if self.whited_out(obj_name).await? {
+            return Err(not_found(obj_name));
+        }
+
+        self.lower.stat(obj_name, with_digest).await
+    }
+
+
+    /// Write an object to the upper layer
+    ///
+    /// Whiteouts stay in place, so a directory that was deleted and written to
+    /// again only shows the new objects.
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        check_name(name)?;
+        self.upper.write_bytes(name, data).await
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        Ok(self.upper.exists(obj_name).await? || self.in_lower(obj_name).await?)
+    }
+
+
+    /// Delete an object from the upper layer and hide it in the lower layer
+    ///
+    /// Without whiteouts, objects present in the lower layer can't be deleted
+    /// and fail with `IdxError::ReadOnly`.
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let in_upper = self.upper.exists(obj_name).await?;
+        let in_lower = self.in_lower(obj_name).await?;
+
+        if !in_upper && !in_lower {
+            return Err(not_found(obj_name));
+        }
+        if in_lower && !self.whiteouts {
+            return Err(IdxError::ReadOnly(obj_name.as_str().to_string()));
+        }
+
+        // directories have to be empty in the merged view
+        let is_empty_dir = match self.list_entries(obj_name).await {
+            Ok(entries) => entries.is_empty(),
+            Err(e) if e.is_not_found() || e.is_not_a_directory() => true,
+            Err(e) => return Err(e),
+        };
+        if !is_empty_dir {
+            let msg = format!("Directory not empty: '{}'", obj_name.as_str());
+            return Err(IdxError::storage_error_msg(msg));
+        }
+
+        if in_upper {
+            // only whiteouts can be left in the directory, these are replaced
+            // by the whiteout of the directory itself
+            let entries = match self.upper.list_entries(obj_name).await {
+                Ok(entries) => entries,
+                Err(e) if e.is_not_found() || e.is_not_a_directory() => vec![],
+                Err(e) => return Err(e),
+            };
+            for entry in entries.iter().filter(|e| hidden_name(e).is_some()) {
+                self.upper.delete(entry.name()).await?;
+            }
+            // implicit directories disappear with their last entry
+            if self.upper.exists(obj_name).await? {
+                self.upper.delete(obj_name).await?;
+            }
+        }
+        if in_lower {
+            self.hide(obj_name).await?;
+        }
+        Ok(())
+    }
+
+
+    /// Rename an object, objects of the lower layer are copied to the upper
+    /// layer and hidden
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        check_name(to)?;
+        if !self.exists(from).await? {
+            return Err(not_found(from));
+        }
+        if self.exists(to).await? {
+            return Err(already_exists(to));
+        }
+
+        if !self.in_lower(from).await? {
+            return self.upper.rename(from, to).await;
+        }
+        if !self.whiteouts {
+            return Err(IdxError::ReadOnly(from.as_str().to_string()));
+        }
+
+        self.copy(from, to).await?;
+        self.delete(from).await
+    }
+
+
+    /// Copy an object into the upper layer
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        check_name(to)?;
+        let byte_data = self.read_bytes(from).await?;
+        if self.exists(to).await? {
+            return Err(already_exists(to));
+        }
+
+        self.upper.write_bytes(to, byte_data).await
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        check_name(dir_name)?;
+        if self.exists(dir_name).await? {
+            return Err(already_exists(dir_name));
+        }
+
+        self.upper.create_dir(dir_name).await
+    }
+}
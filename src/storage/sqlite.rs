@@ -0,0 +1,428 @@
+use super::{AccessStorage,ObjectName,ObjectWriter,DirEntry,EntryKind,ObjectMetadata,ContentDigest};
+use super::transform::CommitWriter;
+use crate::error::*;
+use crate::ObjectNameBuf;
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc,Mutex,MutexGuard};
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+use async_trait::async_trait;
+use tokio::task::spawn_blocking;
+use rusqlite::{Connection,OptionalExtension,Transaction,params};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS objects (
+        name TEXT PRIMARY KEY NOT NULL,
+        is_dir INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        modified INTEGER NOT NULL
+    );
+";
+
+/// Storage keeping objects as rows of a table in a SQLite database
+///
+/// The semantics follow `FileStorage`: directories are rows of their own,
+/// missing parent directories are created on write, and renaming a directory
+/// moves everything below it. Every change runs in a transaction.
+///
+/// Queries run on the blocking thread pool of the runtime, one at a time.
+#[derive(Clone)]
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// Object row without its data
+struct Row {
+    is_dir: bool,
+    size: u64,
+    modified: SystemTime,
+}
+
+
+impl SqliteStorage {
+    /// Open a database file, creating it if it doesn't exist
+    pub fn open(path: impl AsRef<Path>) -> IdxResult<Self> {
+        let conn = Connection::open(path)
+            .map_err(IdxError::storage_error)?;
+
+        Self::with_connection(conn)
+    }
+
+    /// Create a temporary database that only lives in memory
+    pub fn open_in_memory() -> IdxResult<Self> {
+        let conn = Connection::open_in_memory()
+            .map_err(IdxError::storage_error)?;
+
+        Self::with_connection(conn)
+    }
+
+    fn with_connection(conn: Connection) -> IdxResult<Self> {
+        conn.execute_batch(SCHEMA)
+            .map_err(IdxError::storage_error)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    fn lock(&self) -> IdxResult<MutexGuard<'_, Connection>> {
+        self.conn.lock()
+            .map_err(|_| IdxError::storage_error_msg("SQLite connection lock poisoned"))
+    }
+
+    /// Run `f` with the connection on the blocking thread pool
+    async fn with_conn<T, F>(&self, f: F) -> IdxResult<T>
+        where
+            F: FnOnce(&mut Connection) -> IdxResult<T> + Send + 'static,
+            T: Send + 'static
+    {
+        let storage = self.clone();
+
+        spawn_blocking(move || {
+            let mut conn = storage.lock()?;
+            f(&mut conn)
+        }).await.map_err(IdxError::storage_error)?
+    }
+
+    /// Run `f` in a transaction, which is committed if `f` succeeds
+    async fn transaction<T, F>(&self, f: F) -> IdxResult<T>
+        where
+            F: FnOnce(&Transaction<'_>) -> IdxResult<T> + Send + 'static,
+            T: Send + 'static
+    {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()
+                .map_err(IdxError::storage_error)?;
+
+            let rv = f(&tx)?;
+            tx.commit()
+                .map_err(IdxError::storage_error)?;
+            Ok(rv)
+        }).await
+    }
+
+    async fn write_object(&self, name: String, data: Vec<u8>) -> IdxResult<()> {
+        self.transaction(move |tx| {
+            if let Some(row) = find(tx, &name)? {
+                if row.is_dir {
+                    let msg = format!("Is a directory: '{}'", name);
+                    return Err(IdxError::storage_error_msg(msg));
+                }
+            }
+
+            create_parents(tx, &name)?;
+            tx.execute("INSERT OR REPLACE INTO objects (name, is_dir, data, modified) VALUES (?1, 0, ?2, ?3)",
+                       params![name, data, now()])
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }).await
+    }
+}
+
+
+fn not_found(name: &str) -> IdxError {
+    io::Error::new(io::ErrorKind::NotFound, format!("No such object: '{}'", name)).into()
+}
+
+fn already_exists(name: &str) -> IdxError {
+    io::Error::new(io::ErrorKind::AlreadyExists, format!("Object exists: '{}'", name)).into()
+}
+
+fn not_a_directory(name: &str) -> IdxError {
+    io::Error::new(io::ErrorKind::NotADirectory, format!("Not a directory: '{}'", name)).into()
+}
+
+/// Current time as stored in the `modified` column
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0)
+}
+
+fn parent(name: &str) -> Option<&str> {
+    name.rfind('/').map(|pos| &name[..pos])
+}
+
+/// Bounds of the names below a directory, for range queries on the primary key
+///
+/// Names below `dir` start with `dir/`, and `0` directly follows `/`.
+fn prefix_range(dir: &str) -> (String, String) {
+    (format!("{}/", dir), format!("{}0", dir))
+}
+
+
+fn find(conn: &Connection, name: &str) -> IdxResult<Option<Row>> {
+    conn.query_row("SELECT is_dir, length(data), modified FROM objects WHERE name = ?1",
+                   params![name],
+                   |row| Ok(Row {
+                       is_dir: row.get(0)?,
+                       size: row.get::<_, i64>(1)? as u64,
+                       modified: UNIX_EPOCH + Duration::from_nanos(row.get::<_, i64>(2)? as u64),
+                   }))
+        .optional()
+        .map_err(IdxError::storage_error)
+}
+
+fn is_dir(conn: &Connection, name: &str) -> IdxResult<bool> {
+    Ok(name.is_empty() || find(conn, name)?.is_some_and(|row| row.is_dir))
+}
+
+fn has_children(conn: &Connection, dir: &str) -> IdxResult<bool> {
+    let (lower, upper) = prefix_range(dir);
+
+    conn.query_row("SELECT 1 FROM objects WHERE name >= ?1 AND name < ?2 LIMIT 1",
+                   params![lower, upper],
+                   |_| Ok(()))
+        .optional()
+        .map(|row| row.is_some())
+        .map_err(IdxError::storage_error)
+}
+
+/// Names and directory flags of the direct children of a directory
+fn children(conn: &Connection, dir: &str) -> IdxResult<Vec<(String,bool)>> {
+    let read_row = |row: &rusqlite::Row<'_>| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?));
+
+    let rows = if dir.is_empty() {
+        conn.prepare("SELECT name, is_dir FROM objects WHERE instr(name, '/') = 0 ORDER BY name")
+            .and_then(|mut stmt| stmt.query_map([], read_row)?.collect())
+    } else {
+        let (lower, upper) = prefix_range(dir);
+        conn.prepare("SELECT name, is_dir FROM objects WHERE name >= ?1 AND name < ?2 \
+                      AND instr(substr(name, length(?1) + 1), '/') = 0 ORDER BY name")
+            .and_then(|mut stmt| stmt.query_map(params![lower, upper], read_row)?.collect())
+    };
+
+    rows.map_err(IdxError::storage_error)
+}
+
+/// Make sure the parent of `name` exists
+fn check_parent(conn: &Connection, name: &str) -> IdxResult<()> {
+    match parent(name) {
+        Some(dir) if !is_dir(conn, dir)? => Err(not_found(dir)),
+        _ => Ok(()),
+    }
+}
+
+fn create_parents(conn: &Connection, name: &str) -> IdxResult<()> {
+    let dir = match parent(name) {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    match find(conn, dir)? {
+        Some(row) if row.is_dir => Ok(()),
+        Some(_) => Err(not_a_directory(dir)),
+        None => {
+            create_parents(conn, dir)?;
+            conn.execute("INSERT INTO objects (name, is_dir, data, modified) VALUES (?1, 1, x'', ?2)",
+                         params![dir, now()])
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }
+    }
+}
+
+
+#[async_trait]
+impl AccessStorage for SqliteStorage {
+    type ListIntoIter = Vec<String>;
+
+    async fn list(&self, dir_name: ObjectName<'_>) -> IdxResult<Self::ListIntoIter> {
+        let entries = self.list_entries(dir_name).await?;
+
+        Ok(entries.iter()
+            .map(|e| e.name().as_str().to_string())
+            .collect())
+    }
+
+
+    async fn list_entries(&self, dir_name: ObjectName<'_>) -> IdxResult<Vec<DirEntry>> {
+        let dir = dir_name.as_str().to_string();
+
+        self.with_conn(move |conn| {
+            if !dir.is_empty() {
+                match find(conn, &dir)? {
+                    Some(row) if row.is_dir => (),
+                    Some(_) => {
+                        return Err(not_a_directory(&dir));
+                    }
+                    None => return Err(not_found(&dir)),
+                }
+            }
+
+            let mut rv = vec![];
+            for (name, is_dir) in children(conn, &dir)? {
+                let kind = if is_dir { EntryKind::Dir } else { EntryKind::File };
+                rv.push(DirEntry::new(ObjectNameBuf::from_path(name)?, kind));
+            }
+
+            Ok(rv)
+        }).await
+    }
+
+
+    async fn read_bytes(&self, obj_name: ObjectName<'_>) -> IdxResult<Vec<u8>> {
+        let name = obj_name.as_str().to_string();
+
+        self.with_conn(move |conn| {
+            let row = conn.query_row("SELECT is_dir, data FROM objects WHERE name = ?1",
+                                     params![name],
+                                     |row| Ok((row.get::<_, bool>(0)?, row.get::<_, Vec<u8>>(1)?)))
+                .optional()
+                .map_err(IdxError::storage_error)?;
+
+            match row {
+                Some((false, data)) => Ok(data),
+                Some((true, _)) => {
+                    let msg = format!("Is a directory: '{}'", name);
+                    Err(IdxError::storage_error_msg(msg))
+                }
+                None => Err(not_found(&name)),
+            }
+        }).await
+    }
+
+
+    /// Open a writer that stores the object in one transaction once it is
+    /// shut down
+    async fn open_write(&self, obj_name: ObjectName<'_>) -> IdxResult<ObjectWriter> {
+        let storage = self.clone();
+        let name = obj_name.as_str().to_string();
+
+        Ok(Box::new(CommitWriter::new(move |data| async move {
+            storage.write_object(name, data).await
+        })))
+    }
+
+
+    async fn stat(&self, obj_name: ObjectName<'_>, with_digest: bool) -> IdxResult<ObjectMetadata> {
+        let name = obj_name.as_str().to_string();
+        let row = self.with_conn(move |conn| {
+            find(conn, &name)?
+                .ok_or_else(|| not_found(&name))
+        }).await?;
+
+        let digest = if with_digest && !row.is_dir {
+            Some(ContentDigest::of(&self.read_bytes(obj_name).await?))
+        } else {
+            None
+        };
+
+        Ok(ObjectMetadata::new(row.size, Some(row.modified), digest))
+    }
+
+
+    async fn write_bytes<T>(&self, name: ObjectName<'_>, data: T) -> IdxResult<()>
+        where
+            T: AsRef<[u8]> + Unpin + Send
+    {
+        self.write_object(name.as_str().to_string(), data.as_ref().to_vec()).await
+    }
+
+
+    async fn exists(&self, obj_name: ObjectName<'_>) -> IdxResult<bool> {
+        if obj_name.as_str().is_empty() {
+            return Ok(true);
+        }
+
+        let name = obj_name.as_str().to_string();
+        self.with_conn(move |conn| Ok(find(conn, &name)?.is_some())).await
+    }
+
+
+    async fn delete(&self, obj_name: ObjectName<'_>) -> IdxResult<()> {
+        let name = obj_name.as_str().to_string();
+
+        self.transaction(move |tx| {
+            let name = name.as_str();
+            let row = find(tx, name)?
+                .ok_or_else(|| not_found(name))?;
+            if row.is_dir && has_children(tx, name)? {
+                let msg = format!("Directory not empty: '{}'", name);
+                return Err(IdxError::storage_error_msg(msg));
+            }
+
+            tx.execute("DELETE FROM objects WHERE name = ?1", params![name])
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }).await
+    }
+
+
+    /// Rename an object, directories are moved with everything below them
+    async fn rename(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let (from, to) = (from.as_str().to_string(), to.as_str().to_string());
+
+        self.transaction(move |tx| {
+            let (from, to) = (from.as_str(), to.as_str());
+            let row = find(tx, from)?
+                .ok_or_else(|| not_found(from))?;
+            if find(tx, to)?.is_some() {
+                return Err(already_exists(to));
+            }
+            check_parent(tx, to)?;
+
+            if row.is_dir {
+                if to.starts_with(&format!("{}/", from)) {
+                    let msg = format!("Can't move directory '{}' into itself", from);
+                    return Err(IdxError::storage_error_msg(msg));
+                }
+
+                let (lower, upper) = prefix_range(from);
+                tx.execute("UPDATE objects SET name = ?1 || substr(name, length(?2) + 1) \
+                            WHERE name >= ?2 AND name < ?3",
+                           params![format!("{}/", to), lower, upper])
+                    .map_err(IdxError::storage_error)?;
+            }
+
+            tx.execute("UPDATE objects SET name = ?1 WHERE name = ?2", params![to, from])
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }).await
+    }
+
+
+    async fn copy(&self, from: ObjectName<'_>, to: ObjectName<'_>) -> IdxResult<()> {
+        let (from, to) = (from.as_str().to_string(), to.as_str().to_string());
+
+        self.transaction(move |tx| {
+            let (from, to) = (from.as_str(), to.as_str());
+            let row = find(tx, from)?
+                .ok_or_else(|| not_found(from))?;
+            if row.is_dir {
+                let msg = format!("Is a directory: '{}'", from);
+                return Err(IdxError::storage_error_msg(msg));
+            }
+            if find(tx, to)?.is_some() {
+                return Err(already_exists(to));
+            }
+            check_parent(tx, to)?;
+
+            tx.execute("INSERT INTO objects (name, is_dir, data, modified) \
+                        SELECT ?1, 0, data, ?2 FROM objects WHERE name = ?3",
+                       params![to, now(), from])
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }).await
+    }
+
+
+    async fn create_dir(&self, dir_name: ObjectName<'_>) -> IdxResult<()> {
+        let name = dir_name.as_str().to_string();
+
+        self.transaction(move |tx| {
+            let name = name.as_str();
+            if name.is_empty() || find(tx, name)?.is_some() {
+                return Err(already_exists(name));
+            }
+            check_parent(tx, name)?;
+
+            tx.execute("INSERT INTO objects (name, is_dir, data, modified) VALUES (?1, 1, x'', ?2)",
+                       params![name, now()])
+                .map_err(IdxError::storage_error)?;
+            Ok(())
+        }).await
+    }
+}
@@ -0,0 +1,164 @@
+use super::ObjectWriter;
+use crate::error::*;
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context,Poll};
+use tokio::io::AsyncWrite;
+
+type Transform = Box<dyn FnOnce(&[u8]) -> IdxResult<Vec<u8>> + Send>;
+
+enum WriterState {
+    Buffering(Transform),
+    Writing(Vec<u8>, usize),
+    ShuttingDown,
+    Failed,
+}
+
+
+/// Writer collecting data in a buffer until it is transformed as a whole on
+/// shutdown and passed on to the wrapped writer
+///
+/// Used by storage wrappers that can only encode complete objects.
+pub(super) struct TransformWriter {
+    inner: ObjectWriter,
+    buf: Vec<u8>,
+    state: WriterState,
+}
+
+impl TransformWriter {
+    pub(super) fn new<F>(inner: ObjectWriter, transform: F) -> Self
+        where
+            F: FnOnce(&[u8]) -> IdxResult<Vec<u8>> + Send + 'static
+    {
+        Self {
+            inner,
+            buf: vec![],
+            state: WriterState::Buffering(Box::new(transform)),
+        }
+    }
+}
+
+impl AsyncWrite for TransformWriter {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.state {
+            WriterState::Buffering(_) => {
+                this.buf.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            _ => Poll::Ready(Err(io::Error::other("Writer was already shut down"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match &mut this.state {
+                WriterState::Buffering(_) => {
+                    // a failed transform must not leave an incomplete object behind
+                    if let WriterState::Buffering(transform) = std::mem::replace(&mut this.state, WriterState::Failed) {
+                        let data = transform(&this.buf)
+                            .map_err(|e| io::Error::other(e.to_string()))?;
+                        this.buf = vec![];
+                        this.state = WriterState::Writing(data, 0);
+                    }
+                }
+                WriterState::Writing(data, pos) => {
+                    while *pos < data.len() {
+                        let n = futures::ready!(Pin::new(&mut this.inner).poll_write(cx, &data[*pos..]))?;
+                        if n == 0 {
+                            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                        }
+                        *pos += n;
+                    }
+                    this.state = WriterState::ShuttingDown;
+                }
+                WriterState::ShuttingDown => {
+                    return Pin::new(&mut this.inner).poll_shutdown(cx);
+                }
+                WriterState::Failed => {
+                    return Poll::Ready(Err(io::Error::other("Writer failed to encode the object")));
+                }
+            }
+        }
+    }
+}
+
+
+type Commit = Pin<Box<dyn Future<Output = IdxResult<()>> + Send>>;
+
+enum CommitState {
+    Buffering(Box<dyn FnOnce(Vec<u8>) -> Commit + Send>),
+    Committing(Commit),
+    Done,
+}
+
+/// Writer collecting data in a buffer until it is handed to an asynchronous
+/// commit function on shutdown
+///
+/// Used by storages that have to store complete objects in one operation.
+/// Nothing is stored before `shutdown`, so dropping the writer without it
+/// silently discards everything written.
+pub(super) struct CommitWriter {
+    buf: Vec<u8>,
+    state: CommitState,
+}
+
+impl CommitWriter {
+    pub(super) fn new<F,U>(commit: F) -> Self
+        where
+            F: FnOnce(Vec<u8>) -> U + Send + 'static,
+            U: Future<Output = IdxResult<()>> + Send + 'static
+    {
+        Self {
+            buf: vec![],
+            state: CommitState::Buffering(Box::new(move |data| Box::pin(commit(data)))),
+        }
+    }
+}
+
+impl AsyncWrite for CommitWriter {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match this.state {
+            CommitState::Buffering(_) => {
+                this.buf.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+            _ => Poll::Ready(Err(io::Error::other("Writer was already shut down"))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            match &mut this.state {
+                CommitState::Buffering(_) => {
+                    if let CommitState::Buffering(commit) = std::mem::replace(&mut this.state, CommitState::Done) {
+                        this.state = CommitState::Committing(commit(std::mem::take(&mut this.buf)));
+                    }
+                }
+                CommitState::Committing(commit) => {
+                    let res = futures::ready!(commit.as_mut().poll(cx));
+                    this.state = CommitState::Done;
+                    return Poll::Ready(res.map_err(|e| io::Error::other(e.to_string())));
+                }
+                CommitState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
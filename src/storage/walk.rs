@@ -0,0 +1,78 @@
+/// How to treat symbolic links while walking a directory tree
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum SymlinkPolicy {
+    /// Treat links like their targets and descend into linked directories
+    Follow,
+    /// Report links as plain entries, but never descend into them
+    NoFollow,
+    /// Leave links out of the walk entirely
+    Skip,
+}
+
+
+/// Options controlling which objects `AccessStorage::walk` returns
+///
+/// The default walks a single directory level, follows symlinks and reports
+/// directories as well as files, just like `AccessStorage::list`.
+#[derive(Clone,Debug)]
+pub struct WalkOptions {
+    max_depth: Option<usize>,
+    symlinks: SymlinkPolicy,
+    include_dirs: bool,
+}
+
+impl WalkOptions {
+    pub fn new() -> Self {
+        Self {
+            max_depth: Some(1),
+            symlinks: SymlinkPolicy::Follow,
+            include_dirs: true,
+        }
+    }
+
+    /// Descend into subdirectories without a depth limit
+    pub fn recursive(mut self) -> Self {
+        self.max_depth = None;
+        self
+    }
+
+    /// Descend at most `depth` levels, where 1 only covers the start directory
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Select whether directories are returned alongside files
+    pub fn include_dirs(mut self, include: bool) -> Self {
+        self.include_dirs = include;
+        self
+    }
+
+    pub fn depth_limit(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlinks
+    }
+
+    pub fn includes_dirs(&self) -> bool {
+        self.include_dirs
+    }
+
+    /// Check if entries found at `depth` may be descended into
+    pub fn descends_below(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max| depth < max)
+    }
+}
+
+impl Default for WalkOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}